@@ -0,0 +1,236 @@
+use crate::LogEntry;
+use crate::context::RequestContext;
+use crate::models::Callback;
+use crate::templating;
+use axum::body::{Body, Bytes};
+use axum::http::{HeaderMap, Method, header};
+use axum::response::Response;
+use http_body::{Frame, SizeHint};
+use serde_json::Value;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{broadcast, oneshot};
+
+/// Outcome of a callback as reported in the log stream.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct CallbackLog {
+    pub url: String,
+    pub status: Option<u16>,
+    pub attempts: u32,
+}
+
+/// Response body that signals when hyper is done with it, which is when the
+/// response has been written out (or the client went away).
+struct NotifyOnDrop {
+    inner: Body,
+    _sent: oneshot::Sender<()>,
+}
+
+impl http_body::Body for NotifyOnDrop {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Fires the callbacks of a mock once the response has been sent.
+pub fn schedule(
+    response: Response,
+    callbacks: Vec<Callback>,
+    ctx: RequestContext,
+    expectation_id: u64,
+    client: reqwest::Client,
+    log_tx: broadcast::Sender<LogEntry>,
+) -> Response {
+    let (sent_tx, sent_rx) = oneshot::channel();
+    let (parts, body) = response.into_parts();
+    let body = Body::new(NotifyOnDrop {
+        inner: body,
+        _sent: sent_tx,
+    });
+
+    tokio::spawn(async move {
+        // Resolves with an error once the body is dropped
+        let _ = sent_rx.await;
+        for callback in callbacks {
+            tokio::spawn(fire(
+                callback,
+                ctx.clone(),
+                expectation_id,
+                client.clone(),
+                log_tx.clone(),
+            ));
+        }
+    });
+
+    Response::from_parts(parts, body)
+}
+
+async fn fire(
+    callback: Callback,
+    ctx: RequestContext,
+    expectation_id: u64,
+    client: reqwest::Client,
+    log_tx: broadcast::Sender<LogEntry>,
+) {
+    if let Some(ref delay) = callback.delay {
+        let ms = crate::latency::sample(delay);
+        tokio::time::sleep(Duration::from_millis(ms)).await;
+    }
+
+    let method = callback.method.as_deref().unwrap_or("POST").to_uppercase();
+    let mut log = LogEntry {
+        timestamp: String::new(),
+        method: method.clone(),
+        path: callback.url.clone(),
+        body: None,
+        matched: true,
+        expectation_id: Some(expectation_id),
+        latency_ms: None,
+        fault: None,
+        error: None,
+        callback: None,
+        passthrough: false,
+    };
+
+    let request = match build_request(&callback, &method, &ctx) {
+        Ok(request) => request,
+        Err(e) => {
+            tracing::error!("Callback to {} failed: {}", callback.url, e);
+            log.timestamp = chrono::Utc::now().to_rfc3339();
+            log.error = Some(format!("Callback error: {}", e));
+            let _ = log_tx.send(log);
+            return;
+        }
+    };
+    log.path = request.url.clone();
+    log.body = request.body.clone();
+
+    let (max_retries, mut backoff_ms) = callback
+        .retry
+        .as_ref()
+        .map_or((0, 0), |r| (r.max_retries, r.backoff_ms));
+    let mut attempts = 0;
+    let outcome = loop {
+        attempts += 1;
+        let outcome = send(&client, &request).await;
+        let retryable = match outcome {
+            Ok(status) => status == 429 || status >= 500,
+            Err(_) => true,
+        };
+        if !retryable || attempts > max_retries {
+            break outcome;
+        }
+        tracing::warn!(
+            "Callback to {} attempt {} failed, retrying in {}ms",
+            request.url,
+            attempts,
+            backoff_ms
+        );
+        tokio::time::sleep(Duration::from_millis(backoff_ms)).await;
+        backoff_ms = backoff_ms.saturating_mul(2);
+    };
+
+    log.timestamp = chrono::Utc::now().to_rfc3339();
+    match outcome {
+        Ok(status) => {
+            tracing::info!("Callback to {} returned {}", request.url, status);
+            if status >= 400 {
+                log.error = Some(format!("Callback returned {}", status));
+            }
+            log.callback = Some(CallbackLog {
+                url: request.url,
+                status: Some(status),
+                attempts,
+            });
+        }
+        Err(e) => {
+            tracing::error!("Callback to {} failed: {}", request.url, e);
+            log.error = Some(format!("Callback error: {}", e));
+            log.callback = Some(CallbackLog {
+                url: request.url,
+                status: None,
+                attempts,
+            });
+        }
+    }
+    let _ = log_tx.send(log);
+}
+
+/// A callback with its templates resolved, ready to be sent (and resent).
+struct CallbackRequest {
+    method: Method,
+    url: String,
+    headers: HeaderMap,
+    body: Option<Value>,
+}
+
+fn build_request(
+    callback: &Callback,
+    method: &str,
+    ctx: &RequestContext,
+) -> Result<CallbackRequest, String> {
+    let method = Method::from_bytes(method.as_bytes())
+        .map_err(|_| format!("invalid method '{}'", method))?;
+    let url = templating::resolve_template(&callback.url, ctx)?;
+
+    let mut headers = HeaderMap::new();
+    for (key, value) in callback.headers.iter().flatten() {
+        let value = templating::resolve_template(value, ctx)?;
+        let name = header::HeaderName::from_bytes(key.as_bytes())
+            .map_err(|_| format!("invalid header name '{}'", key))?;
+        let value = header::HeaderValue::from_str(&value)
+            .map_err(|_| format!("invalid value for header {}", key))?;
+        headers.insert(name, value);
+    }
+
+    let body = match callback.body {
+        Some(ref body) => Some(templating::resolve_template_value(body.clone(), ctx)?),
+        None => None,
+    };
+    if matches!(body, Some(ref b) if !b.is_string()) && !headers.contains_key(header::CONTENT_TYPE)
+    {
+        headers.insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static("application/json"),
+        );
+    }
+
+    Ok(CallbackRequest {
+        method,
+        url,
+        headers,
+        body,
+    })
+}
+
+async fn send(client: &reqwest::Client, request: &CallbackRequest) -> Result<u16, String> {
+    let mut builder = client
+        .request(request.method.clone(), &request.url)
+        .headers(request.headers.clone());
+    builder = match request.body {
+        // Strings are sent as they are, anything else as JSON
+        Some(Value::String(ref s)) => builder.body(s.clone()),
+        Some(ref other) => builder.body(other.to_string()),
+        None => builder,
+    };
+    builder
+        .send()
+        .await
+        .map(|res| res.status().as_u16())
+        .map_err(|e| e.to_string())
+}
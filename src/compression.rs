@@ -0,0 +1,148 @@
+use std::io::{Read, Write};
+
+/// Encodings mock responses can be compressed with, in order of preference
+/// when the client accepts several equally.
+pub const ENCODINGS: [&str; 4] = ["br", "zstd", "gzip", "deflate"];
+
+/// Largest body [`decode`] produces, so that a small compressed body cannot
+/// expand into gigabytes.
+pub const DECODED_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Why a body could not be decoded.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// Decoding would exceed [`DECODED_LIMIT`]
+    TooLarge,
+    Invalid(String),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::TooLarge => write!(f, "decoded body exceeds {} bytes", DECODED_LIMIT),
+            DecodeError::Invalid(e) => f.write_str(e),
+        }
+    }
+}
+
+impl From<DecodeError> for String {
+    fn from(e: DecodeError) -> Self {
+        e.to_string()
+    }
+}
+
+/// Undoes a `Content-Encoding` header such as `gzip` or `deflate, br`.
+/// Encodings are listed in the order they were applied, so they are removed
+/// from last to first.
+pub fn decode(content_encoding: &str, bytes: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let mut decoded = bytes.to_vec();
+    for encoding in content_encoding.rsplit(',') {
+        let encoding = encoding.trim().to_lowercase();
+        decoded = match encoding.as_str() {
+            "" | "identity" => decoded,
+            "gzip" | "x-gzip" => read_all(flate2::read::GzDecoder::new(&decoded[..]))?,
+            // Some clients send raw deflate instead of the zlib wrapper
+            "deflate" => match read_all(flate2::read::ZlibDecoder::new(&decoded[..])) {
+                Err(DecodeError::Invalid(_)) => {
+                    read_all(flate2::read::DeflateDecoder::new(&decoded[..]))?
+                }
+                zlib => zlib?,
+            },
+            "br" => read_all(brotli::Decompressor::new(&decoded[..], 4096))?,
+            "zstd" => read_all(
+                zstd::stream::read::Decoder::new(&decoded[..])
+                    .map_err(|e| DecodeError::Invalid(e.to_string()))?,
+            )?,
+            other => {
+                return Err(DecodeError::Invalid(format!(
+                    "unsupported content encoding '{}'",
+                    other
+                )));
+            }
+        };
+    }
+    Ok(decoded)
+}
+
+/// Compresses a body with one of [`ENCODINGS`].
+pub fn encode(encoding: &str, bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let io_err = |e: std::io::Error| e.to_string();
+    match encoding {
+        "gzip" => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(bytes).map_err(io_err)?;
+            encoder.finish().map_err(io_err)
+        }
+        "deflate" => {
+            let mut encoder =
+                flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(bytes).map_err(io_err)?;
+            encoder.finish().map_err(io_err)
+        }
+        "br" => {
+            let mut out = Vec::new();
+            {
+                let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                writer.write_all(bytes).map_err(io_err)?;
+            }
+            Ok(out)
+        }
+        "zstd" => zstd::encode_all(bytes, 0).map_err(io_err),
+        other => Err(format!("unsupported content encoding '{}'", other)),
+    }
+}
+
+/// Picks the encoding with the highest q-value in `Accept-Encoding`, or
+/// `None` if the client accepts none of them.
+pub fn negotiate(accept_encoding: &str) -> Option<&'static str> {
+    let mut accepted: Vec<(String, f32)> = accept_encoding
+        .split(',')
+        .filter_map(|part| {
+            let mut params = part.split(';');
+            let coding = params.next()?.trim().to_lowercase();
+            if coding.is_empty() {
+                return None;
+            }
+            let q = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((coding, q))
+        })
+        .collect();
+    // `x-gzip` is an old alias
+    for (coding, _) in accepted.iter_mut() {
+        if coding == "x-gzip" {
+            *coding = "gzip".to_string();
+        }
+    }
+
+    let mut best: Option<(&'static str, f32)> = None;
+    for encoding in ENCODINGS {
+        let q = accepted
+            .iter()
+            .find(|(coding, _)| coding == encoding)
+            .or_else(|| accepted.iter().find(|(coding, _)| coding == "*"))
+            .map(|(_, q)| *q);
+        if let Some(q) = q
+            && q > 0.0
+            && best.is_none_or(|(_, best_q)| q > best_q)
+        {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+fn read_all(reader: impl Read) -> Result<Vec<u8>, DecodeError> {
+    let mut out = Vec::new();
+    reader
+        .take(DECODED_LIMIT as u64 + 1)
+        .read_to_end(&mut out)
+        .map_err(|e| DecodeError::Invalid(e.to_string()))?;
+    if out.len() > DECODED_LIMIT {
+        return Err(DecodeError::TooLarge);
+    }
+    Ok(out)
+}
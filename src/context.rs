@@ -0,0 +1,89 @@
+use axum::http::{HeaderMap, Method, Uri, header};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Everything templates and Lua scripts can see about the incoming request.
+/// The Lua `request` table is this struct serialized, so both stay in sync.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestContext {
+    pub method: String,
+    pub path: String,
+    pub path_segments: Vec<String>,
+    pub query: HashMap<String, String>,
+    pub headers: HashMap<String, String>,
+    pub cookies: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<Value>,
+    pub raw_body: String,
+    /// Number of requests served by the matched expectation, 0 if unmatched.
+    pub counter: u64,
+    /// Fail on unresolved references instead of substituting null.
+    #[serde(skip)]
+    pub strict_templates: bool,
+}
+
+impl RequestContext {
+    pub fn new(
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+        raw_body: &[u8],
+        body: Option<Value>,
+    ) -> Self {
+        let path = uri.path().to_string();
+        let path_segments = path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        // Repeated query keys keep their first value
+        let mut query = HashMap::new();
+        let pairs: Vec<(String, String)> =
+            serde_urlencoded::from_str(uri.query().unwrap_or("")).unwrap_or_default();
+        for (key, value) in pairs {
+            query.entry(key).or_insert(value);
+        }
+
+        let mut header_values: HashMap<String, String> = HashMap::new();
+        for (name, value) in headers.iter() {
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+            header_values
+                .entry(name.as_str().to_string())
+                .and_modify(|existing| {
+                    existing.push_str(", ");
+                    existing.push_str(value);
+                })
+                .or_insert_with(|| value.to_string());
+        }
+
+        let mut cookies = HashMap::new();
+        for cookie_header in headers.get_all(header::COOKIE) {
+            let Ok(cookie_header) = cookie_header.to_str() else {
+                continue;
+            };
+            for pair in cookie_header.split(';') {
+                if let Some((name, value)) = pair.split_once('=') {
+                    cookies.insert(name.trim().to_string(), value.trim().to_string());
+                }
+            }
+        }
+
+        Self {
+            method: method.to_string(),
+            path,
+            path_segments,
+            query,
+            headers: header_values,
+            cookies,
+            body,
+            raw_body: String::from_utf8_lossy(raw_body).into_owned(),
+            counter: 0,
+            strict_templates: false,
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use crate::models::Fault;
+use axum::body::{Body, to_bytes};
+use axum::extract::connect_info::Connected;
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use axum::serve::{IncomingStream, Listener};
+use rand::RngCore;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+
+/// What the connection should do instead of writing the response produced by
+/// hyper.
+enum RawFault {
+    Reset,
+    Write(Vec<u8>),
+}
+
+/// Per-connection switch that lets a handler take over the raw socket. It is
+/// exposed to handlers through `ConnectInfo`.
+#[derive(Clone, Default)]
+pub struct ConnectionHandle(Arc<Mutex<Option<RawFault>>>);
+
+impl ConnectionHandle {
+    fn set(&self, fault: RawFault) {
+        *self.0.lock().unwrap() = Some(fault);
+    }
+
+    fn take(&self) -> Option<RawFault> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+impl Connected<IncomingStream<'_, FaultListener>> for ConnectionHandle {
+    fn connect_info(stream: IncomingStream<'_, FaultListener>) -> Self {
+        stream.io().handle.clone()
+    }
+}
+
+/// TCP listener whose connections can be reset or overwritten by a fault.
+pub struct FaultListener {
+    inner: TcpListener,
+}
+
+impl FaultListener {
+    pub async fn bind(addr: &str) -> io::Result<Self> {
+        Ok(Self {
+            inner: TcpListener::bind(addr).await?,
+        })
+    }
+}
+
+impl Listener for FaultListener {
+    type Io = FaultStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let (inner, addr) = Listener::accept(&mut self.inner).await;
+        let stream = FaultStream {
+            inner,
+            handle: ConnectionHandle::default(),
+            pending: None,
+        };
+        (stream, addr)
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.inner.local_addr()
+    }
+}
+
+pub struct FaultStream {
+    inner: TcpStream,
+    handle: ConnectionHandle,
+    pending: Option<(Vec<u8>, usize)>,
+}
+
+impl AsyncRead for FaultStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for FaultStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.pending.is_none() {
+            match this.handle.take() {
+                Some(RawFault::Reset) => {
+                    // A zero linger makes the kernel send RST once hyper drops the socket
+                    let _ = socket2::SockRef::from(&this.inner).set_linger(Some(Duration::ZERO));
+                    return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+                }
+                Some(RawFault::Write(bytes)) => this.pending = Some((bytes, 0)),
+                None => {}
+            }
+        }
+
+        if let Some((bytes, pos)) = this.pending.as_mut() {
+            while *pos < bytes.len() {
+                let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &bytes[*pos..]))?;
+                if n == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+                *pos += n;
+            }
+            ready!(Pin::new(&mut this.inner).poll_flush(cx))?;
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "connection closed by injected fault",
+            )));
+        }
+
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+pub fn fault_name(fault: Fault) -> &'static str {
+    match fault {
+        Fault::ConnectionReset => "connection_reset",
+        Fault::Hang => "hang",
+        Fault::CloseAfterHeaders => "close_after_headers",
+        Fault::TruncatedBody => "truncated_body",
+        Fault::MalformedChunk => "malformed_chunk",
+        Fault::Garbage => "garbage",
+    }
+}
+
+/// Whether a fault discards the response entirely, so there is no point in
+/// producing one first.
+pub fn replaces_response(fault: Fault) -> bool {
+    matches!(fault, Fault::ConnectionReset | Fault::Hang | Fault::Garbage)
+}
+
+/// Replaces a fully built response with the given connection-level fault.
+pub async fn inject(
+    fault: Fault,
+    handle: Option<&ConnectionHandle>,
+    response: Response,
+) -> Response {
+    tracing::info!("Injecting fault: {}", fault_name(fault));
+    crate::metrics::FAULTS_INJECTED
+        .with_label_values(&[fault_name(fault)])
+        .inc();
+
+    if fault == Fault::Hang {
+        std::future::pending::<()>().await;
+    }
+
+    let Some(handle) = handle else {
+        tracing::error!("No connection handle available, cannot inject fault");
+        return (
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            "Fault injection unavailable on this connection",
+        )
+            .into_response();
+    };
+
+    let raw = match fault {
+        Fault::ConnectionReset => RawFault::Reset,
+        Fault::Garbage => {
+            let mut garbage = vec![0u8; 512];
+            crate::rng::with_rng(|rng| rng.fill_bytes(&mut garbage));
+            RawFault::Write(garbage)
+        }
+        _ => {
+            let (parts, body) = response.into_parts();
+            let body = to_bytes(body, usize::MAX).await.unwrap_or_default();
+
+            let mut raw = format!(
+                "HTTP/1.1 {} {}\r\n",
+                parts.status.as_u16(),
+                parts.status.canonical_reason().unwrap_or("")
+            )
+            .into_bytes();
+            for (name, value) in parts.headers.iter() {
+                if name == header::CONTENT_LENGTH || name == header::TRANSFER_ENCODING {
+                    continue;
+                }
+                raw.extend_from_slice(name.as_str().as_bytes());
+                raw.extend_from_slice(b": ");
+                raw.extend_from_slice(value.as_bytes());
+                raw.extend_from_slice(b"\r\n");
+            }
+
+            match fault {
+                Fault::MalformedChunk => {
+                    raw.extend_from_slice(b"Transfer-Encoding: chunked\r\n\r\n");
+                    // Chunk sizes must be hex, so this can never be parsed
+                    raw.extend_from_slice(b"zz\r\n");
+                    raw.extend_from_slice(&body);
+                    raw.extend_from_slice(b"\r\n");
+                }
+                Fault::TruncatedBody => {
+                    let declared = body.len().max(1);
+                    raw.extend_from_slice(
+                        format!("Content-Length: {}\r\n\r\n", declared).as_bytes(),
+                    );
+                    raw.extend_from_slice(&body[..body.len() / 2]);
+                }
+                _ => {
+                    raw.extend_from_slice(
+                        format!("Content-Length: {}\r\n\r\n", body.len().max(1)).as_bytes(),
+                    );
+                }
+            }
+            RawFault::Write(raw)
+        }
+    };
+
+    handle.set(raw);
+    // Hyper still needs something to write so the stream gets a chance to take over
+    Response::new(Body::empty())
+}
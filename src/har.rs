@@ -0,0 +1,482 @@
+use crate::context::RequestContext;
+use crate::models::{Expectation, RecordConfig};
+use crate::{compression, recording, serializers};
+use axum::body::{Body, Bytes};
+use axum::http::{HeaderMap, Method, StatusCode, Uri, header, request};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use http_body::{Frame, SizeHint};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+/// Request and response bodies are journaled up to this size.
+const MAX_JOURNALED_BODY: usize = 1024 * 1024;
+
+/// An HTTP Archive (HAR 1.2) document. Only the fields Mimicrab reads or
+/// writes are modelled, everything else is ignored on import.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Har {
+    pub log: Log,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Log {
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub creator: Creator,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Creator {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    #[serde(default)]
+    pub started_date_time: String,
+    #[serde(default)]
+    pub time: f64,
+    pub request: Request,
+    pub response: Response,
+    #[serde(default)]
+    pub cache: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    pub timings: Timings,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NameValue {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Request {
+    pub method: String,
+    pub url: String,
+    #[serde(default)]
+    pub http_version: String,
+    #[serde(default)]
+    pub cookies: Vec<NameValue>,
+    #[serde(default)]
+    pub headers: Vec<NameValue>,
+    #[serde(default)]
+    pub query_string: Vec<NameValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_data: Option<PostData>,
+    #[serde(default = "unknown_size")]
+    pub headers_size: i64,
+    #[serde(default = "unknown_size")]
+    pub body_size: i64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostData {
+    #[serde(default)]
+    pub mime_type: String,
+    #[serde(default)]
+    pub text: String,
+    /// `base64` for binary bodies, like response content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Response {
+    pub status: u16,
+    #[serde(default)]
+    pub status_text: String,
+    #[serde(default)]
+    pub http_version: String,
+    #[serde(default)]
+    pub cookies: Vec<NameValue>,
+    #[serde(default)]
+    pub headers: Vec<NameValue>,
+    #[serde(default)]
+    pub content: Content,
+    #[serde(default, rename = "redirectURL")]
+    pub redirect_url: String,
+    #[serde(default = "unknown_size")]
+    pub headers_size: i64,
+    #[serde(default = "unknown_size")]
+    pub body_size: i64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Content {
+    #[serde(default)]
+    pub size: i64,
+    #[serde(default)]
+    pub mime_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Timings {
+    pub send: f64,
+    pub wait: f64,
+    pub receive: f64,
+}
+
+fn unknown_size() -> i64 {
+    -1
+}
+
+fn name_values(headers: &HeaderMap) -> Vec<NameValue> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| {
+            Some(NameValue {
+                name: name.as_str().to_string(),
+                value: value.to_str().ok()?.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Builds a header map from HAR headers. HTTP/2 pseudo headers such as
+/// `:authority` and anything else that is not a valid header are dropped.
+fn header_map(headers: &[NameValue]) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    for NameValue { name, value } in headers {
+        if let Ok(name) = header::HeaderName::from_bytes(name.as_bytes())
+            && let Ok(value) = header::HeaderValue::from_str(value)
+        {
+            map.append(name, value);
+        }
+    }
+    map
+}
+
+/// Text goes into HAR content as is, anything else base64 encoded.
+fn content_text(bytes: &[u8]) -> (Option<String>, Option<String>) {
+    if bytes.is_empty() {
+        return (None, None);
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(text) => (Some(text.to_string()), None),
+        Err(_) => (Some(BASE64.encode(bytes)), Some("base64".to_string())),
+    }
+}
+
+/// The start of a body that fits in the journal, cut before a character
+/// that would not fit whole so text stays text.
+fn journaled(body: &[u8]) -> &[u8] {
+    if body.len() <= MAX_JOURNALED_BODY {
+        return body;
+    }
+    let cut = &body[..MAX_JOURNALED_BODY];
+    match std::str::from_utf8(cut) {
+        Err(e) if e.error_len().is_none() => &cut[..e.valid_up_to()],
+        _ => cut,
+    }
+}
+
+/// Converts a HAR entry into an expectation that matches on method and path
+/// plus whatever `config` selects, exactly like a recorded proxy exchange.
+/// Returns `None` for entries that cannot be replayed, e.g. aborted requests
+/// without a status or binary responses.
+pub fn to_expectation(entry: &Entry, config: &RecordConfig) -> Option<Expectation> {
+    let request = &entry.request;
+    let method = Method::from_bytes(request.method.as_bytes()).ok()?;
+    let uri: Uri = request.url.parse().ok()?;
+    let headers = header_map(&request.headers);
+    let (body, mime_type) = match request.post_data {
+        Some(ref post_data) => (
+            match post_data.encoding.as_deref() {
+                Some("base64") => BASE64.decode(&post_data.text).ok()?,
+                _ => post_data.text.as_bytes().to_vec(),
+            },
+            Some(post_data.mime_type.as_str()),
+        ),
+        None => (Vec::new(), None),
+    };
+    let content_type = mime_type
+        .filter(|mime_type| !mime_type.is_empty())
+        .or_else(|| {
+            headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+        });
+    let ctx = RequestContext::new(
+        &method,
+        &uri,
+        &headers,
+        &body,
+        serializers::decode_body(content_type, &body),
+    );
+
+    let response = &entry.response;
+    let status = StatusCode::from_u16(response.status).ok()?;
+    let mut response_headers = header_map(&response.headers);
+    // HAR content has already been decoded
+    response_headers.remove(header::CONTENT_ENCODING);
+    if !response_headers.contains_key(header::CONTENT_TYPE)
+        && let Ok(value) = header::HeaderValue::from_str(&response.content.mime_type)
+        && !value.is_empty()
+    {
+        response_headers.insert(header::CONTENT_TYPE, value);
+    }
+    let body = match (&response.content.text, response.content.encoding.as_deref()) {
+        (Some(text), Some("base64")) => BASE64.decode(text).ok()?,
+        (Some(text), _) => text.clone().into_bytes(),
+        (None, _) => Vec::new(),
+    };
+
+    recording::to_expectation(config, &ctx, status, &response_headers, &body)
+}
+
+/// Bounded record of the requests Mimicrab served and its responses, oldest
+/// first.
+pub struct Journal {
+    entries: Mutex<VecDeque<Entry>>,
+    capacity: usize,
+}
+
+impl Journal {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::new()),
+            capacity,
+        }
+    }
+
+    fn push(&self, entry: Entry) {
+        let mut entries = self.entries.lock().unwrap();
+        while entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    pub fn to_har(&self) -> Har {
+        Har {
+            log: Log {
+                version: "1.2".to_string(),
+                creator: Creator {
+                    name: "mimicrab".to_string(),
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                },
+                entries: self.entries.lock().unwrap().iter().cloned().collect(),
+            },
+        }
+    }
+
+    /// Wraps a served response so that the exchange is journaled once the
+    /// body has been sent.
+    pub fn record(
+        self: &Arc<Self>,
+        request: Request,
+        started: chrono::DateTime<chrono::Utc>,
+        start: Instant,
+        response: axum::response::Response,
+    ) -> axum::response::Response {
+        if self.capacity == 0 {
+            return response;
+        }
+        let (parts, body) = response.into_parts();
+        let pending = Pending {
+            journal: Arc::clone(self),
+            started,
+            start,
+            request,
+            status: parts.status,
+            http_version: format!("{:?}", parts.version),
+            headers: parts.headers.clone(),
+        };
+        let body = Body::new(JournalBody {
+            inner: body,
+            captured: Vec::new(),
+            pending: Some(pending),
+        });
+        axum::response::Response::from_parts(parts, body)
+    }
+}
+
+/// Describes an incoming request the way HAR does.
+pub fn request_entry(parts: &request::Parts, body: &[u8]) -> Request {
+    let host = parts
+        .headers
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("localhost");
+    let path_and_query = parts
+        .uri
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/");
+    let query_string: Vec<(String, String)> =
+        serde_urlencoded::from_str(parts.uri.query().unwrap_or("")).unwrap_or_default();
+    let cookies = parts
+        .headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            Some(NameValue {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+        })
+        .collect();
+    let (text, encoding) = content_text(journaled(body));
+    let post_data = text.map(|text| PostData {
+        mime_type: parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string(),
+        text,
+        encoding,
+    });
+
+    Request {
+        method: parts.method.to_string(),
+        url: format!("http://{}{}", host, path_and_query),
+        http_version: format!("{:?}", parts.version),
+        cookies,
+        headers: name_values(&parts.headers),
+        query_string: query_string
+            .into_iter()
+            .map(|(name, value)| NameValue { name, value })
+            .collect(),
+        post_data,
+        headers_size: -1,
+        body_size: body.len() as i64,
+    }
+}
+
+/// Everything about an exchange except the response body, which is only
+/// known once it has been sent.
+struct Pending {
+    journal: Arc<Journal>,
+    started: chrono::DateTime<chrono::Utc>,
+    start: Instant,
+    request: Request,
+    status: StatusCode,
+    http_version: String,
+    headers: HeaderMap,
+}
+
+impl Pending {
+    fn finish(self, body: &[u8]) {
+        let content_type = self
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        // HAR content is decoded, the body size is what went over the wire
+        let decoded = match self
+            .headers
+            .get(header::CONTENT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+        {
+            Some(encoding) => compression::decode(encoding, body).unwrap_or_else(|_| body.to_vec()),
+            None => body.to_vec(),
+        };
+        let (text, encoding) = content_text(&decoded);
+        let location = self
+            .headers
+            .get(header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let elapsed_ms = self.start.elapsed().as_secs_f64() * 1000.0;
+
+        self.journal.push(Entry {
+            started_date_time: self.started.to_rfc3339(),
+            time: elapsed_ms,
+            request: self.request,
+            response: Response {
+                status: self.status.as_u16(),
+                status_text: self
+                    .status
+                    .canonical_reason()
+                    .unwrap_or_default()
+                    .to_string(),
+                http_version: self.http_version,
+                cookies: Vec::new(),
+                headers: name_values(&self.headers),
+                content: Content {
+                    size: decoded.len() as i64,
+                    mime_type: content_type,
+                    text,
+                    encoding,
+                },
+                redirect_url: location,
+                headers_size: -1,
+                body_size: body.len() as i64,
+            },
+            cache: serde_json::Map::new(),
+            timings: Timings {
+                send: 0.0,
+                wait: elapsed_ms,
+                receive: 0.0,
+            },
+        });
+    }
+}
+
+/// Response body that copies what is sent and journals the exchange when
+/// hyper is done with it.
+struct JournalBody {
+    inner: Body,
+    captured: Vec<u8>,
+    pending: Option<Pending>,
+}
+
+impl http_body::Body for JournalBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(ref frame))) = poll
+            && let Some(data) = frame.data_ref()
+        {
+            let room = MAX_JOURNALED_BODY.saturating_sub(self.captured.len());
+            self.captured
+                .extend_from_slice(&data[..data.len().min(room)]);
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for JournalBody {
+    fn drop(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            pending.finish(&self.captured);
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use crate::models::{Latency, LatencyDistribution};
+use rand::Rng;
+use rand_distr::{Distribution, LogNormal, Normal};
+
+/// Draws a single delay in milliseconds from the configured latency.
+pub fn sample(latency: &Latency) -> u64 {
+    crate::rng::with_rng(|rng| sample_with(latency, rng))
+}
+
+fn sample_with(latency: &Latency, rng: &mut impl Rng) -> u64 {
+    match *latency {
+        Latency::Fixed(ms) => ms,
+        Latency::Uniform { min, max } => {
+            if max <= min {
+                min
+            } else {
+                rng.random_range(min..=max)
+            }
+        }
+        Latency::Normal {
+            mean,
+            stddev,
+            distribution,
+        } => {
+            let stddev = stddev.max(0.0);
+            let value = match distribution {
+                LatencyDistribution::Normal => Normal::new(mean, stddev)
+                    .map(|d| d.sample(rng))
+                    .unwrap_or(mean),
+                LatencyDistribution::LogNormal => {
+                    // Convert the desired mean/stddev of the delay itself into
+                    // the parameters of the underlying normal distribution.
+                    if mean <= 0.0 {
+                        0.0
+                    } else {
+                        let sigma2 = (1.0 + (stddev * stddev) / (mean * mean)).ln();
+                        let mu = mean.ln() - sigma2 / 2.0;
+                        LogNormal::new(mu, sigma2.sqrt())
+                            .map(|d| d.sample(rng))
+                            .unwrap_or(mean)
+                    }
+                }
+            };
+            value.max(0.0).round() as u64
+        }
+        Latency::Percentiles { p50, p95, p99 } => {
+            let u: f64 = rng.random();
+            percentile_value(u, p50 as f64, p95 as f64, p99 as f64)
+                .max(0.0)
+                .round() as u64
+        }
+    }
+}
+
+/// Piecewise-linear inverse CDF through (0, 0), (0.5, p50), (0.95, p95),
+/// (0.99, p99) and (1.0, p99 + (p99 - p95)), so the top percent still has
+/// a tail beyond p99.
+fn percentile_value(u: f64, p50: f64, p95: f64, p99: f64) -> f64 {
+    let points = [
+        (0.0, 0.0),
+        (0.5, p50),
+        (0.95, p95),
+        (0.99, p99),
+        (1.0, p99 + (p99 - p95).max(0.0)),
+    ];
+    for window in points.windows(2) {
+        let (q0, v0) = window[0];
+        let (q1, v1) = window[1];
+        if u <= q1 {
+            return v0 + (v1 - v0) * (u - q0) / (q1 - q0);
+        }
+    }
+    points[points.len() - 1].1
+}
+
+/// Sleeps for the given delay and records it in the latency histogram.
+pub async fn delay(ms: u64, path: &str) {
+    crate::metrics::INJECTED_LATENCY
+        .with_label_values(&[path])
+        .observe(ms as f64 / 1000.0);
+    if ms > 0 {
+        tracing::info!("Applying latency delay: {}ms", ms);
+        tokio::time::sleep(std::time::Duration::from_millis(ms)).await;
+    }
+}
@@ -1,16 +1,27 @@
+mod callbacks;
+mod compression;
+mod context;
+mod faults;
+mod har;
 mod kubernetes;
+mod latency;
 mod matcher;
 mod metrics;
 mod models;
+mod proxy;
+mod recording;
+mod rng;
+mod serializers;
+mod shadow;
 mod templating;
 
-use arc_swap::ArcSwap;
+use arc_swap::{ArcSwap, ArcSwapOption};
 use axum::body::Body;
 use axum::body::Bytes;
 use axum::http;
 use axum::{
     Json, Router,
-    extract::{Path as AxPath, Request, State},
+    extract::{ConnectInfo, DefaultBodyLimit, Path as AxPath, Query, Request, State},
     http::{HeaderMap, StatusCode, header},
     response::{
         IntoResponse, Response,
@@ -19,11 +30,13 @@ use axum::{
     routing::{get, post, put},
 };
 use clap::Parser;
+use context::RequestContext;
 use futures::stream::Stream;
 use http_body_util::BodyExt;
 use kube::{Client, Config};
 use mlua::{Lua, LuaSerdeExt, Table, Value as LuaValue};
 use models::Expectation;
+use rand::Rng;
 use rust_embed_for_web::{EmbedableFile, RustEmbed};
 use serde_json::{Value, json};
 use std::{convert::Infallible, fs, sync::Arc};
@@ -31,6 +44,12 @@ use tokio::sync::broadcast;
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::EnvFilter;
 
+/// HAR files of whole browser sessions easily exceed the default body limit.
+const HAR_BODY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Number of drift reports kept for `/_admin/drift`.
+const DRIFT_REPORTS: usize = 100;
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -39,6 +58,33 @@ struct Args {
 
     #[arg(short, long, default_value = "expectations.json")]
     expectations: String,
+
+    /// Seed for jitter and latency sampling, makes runs reproducible
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Fail requests whose templates reference missing values instead of
+    /// substituting null
+    #[arg(long)]
+    strict_templates: bool,
+
+    /// Record proxied traffic as expectations
+    #[arg(long)]
+    record: bool,
+
+    /// What recorded expectations match on besides method and path:
+    /// query, body or header:<name>
+    #[arg(long, value_delimiter = ',', requires = "record")]
+    record_match: Vec<String>,
+
+    /// Upstream that unmatched requests are proxied to instead of getting a
+    /// 404
+    #[arg(long)]
+    fallback_proxy: Option<String>,
+
+    /// Number of served requests kept for the HAR journal
+    #[arg(long, default_value_t = 1000)]
+    journal_size: usize,
 }
 #[derive(Clone, Debug, serde::Serialize)]
 struct LogEntry {
@@ -48,6 +94,12 @@ struct LogEntry {
     body: Option<Value>,
     matched: bool,
     expectation_id: Option<u64>,
+    latency_ms: Option<u64>,
+    fault: Option<models::Fault>,
+    error: Option<String>,
+    callback: Option<callbacks::CallbackLog>,
+    /// Unmatched request forwarded to the fallback proxy
+    passthrough: bool,
 }
 
 struct AppState {
@@ -57,7 +109,14 @@ struct AppState {
     config_map_name: String,
     namespace: String,
     proxy_client: reqwest::Client,
+    proxy_clients: proxy::Clients,
+    balancer: proxy::Balancer,
     expectations_path: String,
+    strict_templates: bool,
+    record: Option<models::RecordConfig>,
+    journal: Arc<har::Journal>,
+    fallback: ArcSwapOption<models::ProxyConfig>,
+    drift: shadow::Drift,
 }
 
 #[derive(RustEmbed)]
@@ -76,7 +135,21 @@ async fn main() {
 
     let args = Args::parse();
     let port = args.port;
+    if let Some(seed) = args.seed {
+        rng::seed(seed);
+    }
     let expectations_path = args.expectations.clone();
+    let record = if args.record {
+        match recording::parse_match_keys(&args.record_match) {
+            Ok(config) => Some(config),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(2);
+            }
+        }
+    } else {
+        None
+    };
     let (log_tx, _) = broadcast::channel(100);
 
     let provider = rustls::crypto::ring::default_provider();
@@ -110,10 +183,7 @@ async fn main() {
 
     let expectations = Arc::new(ArcSwap::from_pointee(initial_expectations));
 
-    let proxy_client = reqwest::Client::builder()
-        .user_agent("mimicrab/0.1.0")
-        .build()
-        .unwrap();
+    let proxy_client = proxy::client_builder().build().unwrap();
 
     let state = Arc::new(AppState {
         expectations: Arc::clone(&expectations),
@@ -121,8 +191,18 @@ async fn main() {
         kube_client,
         config_map_name,
         namespace,
+        proxy_clients: proxy::Clients::new(proxy_client.clone()),
+        balancer: proxy::Balancer::default(),
         proxy_client,
         expectations_path,
+        strict_templates: args.strict_templates,
+        record,
+        journal: Arc::new(har::Journal::new(args.journal_size)),
+        fallback: ArcSwapOption::from_pointee(args.fallback_proxy.map(|url| models::ProxyConfig {
+            url,
+            ..Default::default()
+        })),
+        drift: shadow::Drift::new(DRIFT_REPORTS),
     });
 
     if let Some(ref client) = state.kube_client {
@@ -141,6 +221,17 @@ async fn main() {
         .route("/logs/stream", get(stream_logs))
         .route("/export", get(export_mocks))
         .route("/import", post(import_mocks))
+        .route(
+            "/import/har",
+            post(import_har).layer(DefaultBodyLimit::max(HAR_BODY_LIMIT)),
+        )
+        .route("/journal.har", get(export_journal))
+        .route("/seed", post(seed_rng))
+        .route(
+            "/fallback",
+            get(get_fallback).put(set_fallback).delete(clear_fallback),
+        )
+        .route("/drift", get(get_drift).delete(clear_drift))
         .route("/metrics", get(metrics_handler));
 
     let app = Router::new()
@@ -156,9 +247,14 @@ async fn main() {
         .with_state(state);
 
     let addr = format!("0.0.0.0:{}", port);
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+    let listener = faults::FaultListener::bind(&addr).await.unwrap();
     tracing::info!("Mock server running on http://{}", addr);
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<faults::ConnectionHandle>(),
+    )
+    .await
+    .unwrap();
 }
 
 fn load_expectations(path: &str) -> Vec<Expectation> {
@@ -177,6 +273,16 @@ fn save_expectations(path: &str, expectations: &[Expectation]) {
     tracing::info!("Local expectations saved to {}", path);
 }
 
+/// Writes expectations to the ConfigMap in Kubernetes, or to the local file.
+async fn persist_expectations(state: &AppState, mocks: &[Expectation]) {
+    if let Some(ref client) = state.kube_client {
+        kubernetes::sync_to_configmap(client, &state.namespace, &state.config_map_name, mocks)
+            .await;
+    } else {
+        save_expectations(&state.expectations_path, mocks);
+    }
+}
+
 // Helper struct for adding/cloning mocks
 #[derive(Debug, serde::Deserialize)]
 struct MockRequest {
@@ -190,10 +296,16 @@ async fn list_mocks(State(state): State<Arc<AppState>>) -> Json<Vec<Expectation>
     Json((**state.expectations.load()).clone())
 }
 
+fn invalid_mock(error: String) -> (StatusCode, Json<Value>) {
+    tracing::warn!("Rejecting invalid mock: {}", error);
+    (StatusCode::BAD_REQUEST, Json(json!({ "error": error })))
+}
+
 async fn add_mock(
     State(state): State<Arc<AppState>>,
     Json(req): Json<MockRequest>,
-) -> (StatusCode, Json<Expectation>) {
+) -> Result<(StatusCode, Json<Expectation>), (StatusCode, Json<Value>)> {
+    templating::validate_mock(&req.response, state.strict_templates).map_err(invalid_mock)?;
     let mut mocks = (*state.expectations.load_full()).clone();
 
     let id = req
@@ -208,35 +320,27 @@ async fn add_mock(
     mocks.push(new_mock.clone());
     state.expectations.store(Arc::new(mocks.clone()));
 
-    if let Some(ref client) = state.kube_client {
-        kubernetes::sync_to_configmap(client, &state.namespace, &state.config_map_name, &mocks)
-            .await;
-    } else {
-        save_expectations(&state.expectations_path, &mocks);
-    }
+    persist_expectations(&state, &mocks).await;
 
-    (StatusCode::CREATED, Json(new_mock))
+    Ok((StatusCode::CREATED, Json(new_mock)))
 }
 
 async fn update_mock(
     State(state): State<Arc<AppState>>,
     AxPath(id): AxPath<u64>,
     Json(updated_mock): Json<Expectation>,
-) -> StatusCode {
+) -> Result<StatusCode, (StatusCode, Json<Value>)> {
+    templating::validate_mock(&updated_mock.response, state.strict_templates)
+        .map_err(invalid_mock)?;
     let mut mocks = (*state.expectations.load_full()).clone();
     if let Some(pos) = mocks.iter().position(|m| m.id == id) {
         mocks[pos] = updated_mock;
         state.expectations.store(Arc::new(mocks.clone()));
 
-        if let Some(ref client) = state.kube_client {
-            kubernetes::sync_to_configmap(client, &state.namespace, &state.config_map_name, &mocks)
-                .await;
-        } else {
-            save_expectations(&state.expectations_path, &mocks);
-        }
-        StatusCode::OK
+        persist_expectations(&state, &mocks).await;
+        Ok(StatusCode::OK)
     } else {
-        StatusCode::NOT_FOUND
+        Ok(StatusCode::NOT_FOUND)
     }
 }
 
@@ -246,12 +350,7 @@ async fn delete_mock(State(state): State<Arc<AppState>>, AxPath(id): AxPath<u64>
         mocks.remove(pos);
         state.expectations.store(Arc::new(mocks.clone()));
 
-        if let Some(ref client) = state.kube_client {
-            kubernetes::sync_to_configmap(client, &state.namespace, &state.config_map_name, &mocks)
-                .await;
-        } else {
-            save_expectations(&state.expectations_path, &mocks);
-        }
+        persist_expectations(&state, &mocks).await;
         StatusCode::NO_CONTENT
     } else {
         StatusCode::NOT_FOUND
@@ -265,15 +364,115 @@ async fn export_mocks(State(state): State<Arc<AppState>>) -> Json<Vec<Expectatio
 async fn import_mocks(
     State(state): State<Arc<AppState>>,
     Json(new_mocks): Json<Vec<Expectation>>,
-) -> StatusCode {
+) -> Result<StatusCode, (StatusCode, Json<Value>)> {
+    for mock in &new_mocks {
+        templating::validate_mock(&mock.response, state.strict_templates)
+            .map_err(|e| invalid_mock(format!("mock {}: {}", mock.id, e)))?;
+    }
     state.expectations.store(Arc::new(new_mocks.clone()));
 
-    if let Some(ref client) = state.kube_client {
-        kubernetes::sync_to_configmap(client, &state.namespace, &state.config_map_name, &new_mocks)
-            .await;
-    } else {
-        save_expectations(&state.expectations_path, &new_mocks);
+    persist_expectations(&state, &new_mocks).await;
+    Ok(StatusCode::OK)
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct HarImportParams {
+    /// What the expectations match on besides method and path, like
+    /// `--record-match`
+    #[serde(rename = "match")]
+    match_keys: Option<String>,
+}
+
+async fn import_har(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HarImportParams>,
+    Json(archive): Json<har::Har>,
+) -> Result<(StatusCode, Json<Value>), (StatusCode, Json<Value>)> {
+    let keys: Vec<String> = params
+        .match_keys
+        .iter()
+        .flat_map(|keys| keys.split(','))
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty())
+        .collect();
+    let config = recording::parse_match_keys(&keys)
+        .map_err(|e| (StatusCode::BAD_REQUEST, Json(json!({ "error": e }))))?;
+
+    let mut mocks = (*state.expectations.load_full()).clone();
+    let mut next_id = mocks.iter().map(|m| m.id).max().unwrap_or(0) + 1;
+    let (mut imported, mut skipped) = (0, 0);
+    for entry in &archive.log.entries {
+        match har::to_expectation(entry, &config) {
+            Some(mut exp) if !recording::is_duplicate(&mocks, &exp) => {
+                exp.id = next_id;
+                next_id += 1;
+                mocks.push(exp);
+                imported += 1;
+            }
+            _ => skipped += 1,
+        }
     }
+    tracing::info!("Imported {} HAR entries, skipped {}", imported, skipped);
+    state.expectations.store(Arc::new(mocks.clone()));
+
+    persist_expectations(&state, &mocks).await;
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "imported": imported, "skipped": skipped })),
+    ))
+}
+
+async fn export_journal(State(state): State<Arc<AppState>>) -> Json<har::Har> {
+    Json(state.journal.to_har())
+}
+
+async fn get_fallback(State(state): State<Arc<AppState>>) -> Json<Option<models::ProxyConfig>> {
+    Json(state.fallback.load_full().map(|config| (*config).clone()))
+}
+
+async fn set_fallback(
+    State(state): State<Arc<AppState>>,
+    Json(config): Json<models::ProxyConfig>,
+) -> Result<StatusCode, (StatusCode, Json<Value>)> {
+    proxy::validate(&config, state.strict_templates)
+        .map_err(|e| (StatusCode::BAD_REQUEST, Json(json!({ "error": e }))))?;
+    tracing::info!(
+        "Forwarding unmatched requests to {}",
+        config.urls().join(", ")
+    );
+    state.fallback.store(Some(Arc::new(config)));
+    Ok(StatusCode::OK)
+}
+
+async fn clear_fallback(State(state): State<Arc<AppState>>) -> StatusCode {
+    state.fallback.store(None);
+    StatusCode::NO_CONTENT
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DriftParams {
+    expectation: Option<u64>,
+}
+
+async fn get_drift(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DriftParams>,
+) -> Json<Vec<shadow::DriftReport>> {
+    Json(state.drift.reports(params.expectation))
+}
+
+async fn clear_drift(State(state): State<Arc<AppState>>) -> StatusCode {
+    state.drift.clear();
+    StatusCode::NO_CONTENT
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SeedRequest {
+    seed: u64,
+}
+
+async fn seed_rng(Json(req): Json<SeedRequest>) -> StatusCode {
+    rng::seed(req.seed);
     StatusCode::OK
 }
 
@@ -353,37 +552,11 @@ async fn static_handler(path: Option<AxPath<String>>, headers: HeaderMap) -> Res
     }
 }
 
-async fn execute_lua_script(
-    script: &str,
-    method: &str,
-    path: &str,
-    headers: &HeaderMap,
-    body: &Option<Value>,
-) -> Result<Response, String> {
+async fn execute_lua_script(script: &str, ctx: &RequestContext) -> Result<Response, String> {
     let lua = Lua::new();
 
-    // Prepare request table
-    let req_table = lua.create_table().map_err(|e| e.to_string())?;
-    req_table.set("method", method).map_err(|e| e.to_string())?;
-    req_table.set("path", path).map_err(|e| e.to_string())?;
-
-    let headers_table = lua.create_table().map_err(|e| e.to_string())?;
-    for (name, value) in headers.iter() {
-        headers_table
-            .set(name.as_str(), value.to_str().unwrap())
-            .map_err(|e| e.to_string())?;
-    }
-    req_table
-        .set("headers", headers_table)
-        .map_err(|e| e.to_string())?;
-
-    if let Some(body_val) = body {
-        let body_lua = lua.to_value(body_val).map_err(|e| e.to_string())?;
-        req_table.set("body", body_lua).map_err(|e| e.to_string())?;
-    }
-
-    dbg!(&req_table);
-
+    // Prepare request table from the same context templates use
+    let req_table = lua.to_value(ctx).map_err(|e| e.to_string())?;
     lua.globals()
         .set("request", req_table)
         .map_err(|e| e.to_string())?;
@@ -433,34 +606,95 @@ async fn execute_lua_script(
 
 async fn handle_request(State(state): State<Arc<AppState>>, req: Request) -> Response {
     let start = std::time::Instant::now();
-    let (parts, body) = req.into_parts();
+    let started = chrono::Utc::now();
+    let (mut parts, body) = req.into_parts();
+    let upgrade = match proxy::is_upgrade(&parts.headers) {
+        true => parts.extensions.remove::<hyper::upgrade::OnUpgrade>(),
+        false => None,
+    };
     let path = parts.uri.path();
     let method = &parts.method;
     let headers = &parts.headers;
+    let connection = parts
+        .extensions
+        .get::<ConnectInfo<faults::ConnectionHandle>>()
+        .map(|ConnectInfo(handle)| handle.clone());
 
-    let body_bytes = body
-        .collect()
-        .await
-        .map(|b| b.to_bytes())
-        .unwrap_or_default();
-    let body_json: Option<Value> = serde_json::from_slice(&body_bytes).ok();
+    let expectations = state.expectations.load();
+    // Bodies that only a proxy needs go upstream as they arrive
+    let (body_bytes, stream) = if streams_request(&state, &expectations, &parts) {
+        (Bytes::new(), Some(body))
+    } else {
+        let body_bytes = body
+            .collect()
+            .await
+            .map(|b| b.to_bytes())
+            .unwrap_or_default();
+        (body_bytes, None)
+    };
+    let journal_request = har::request_entry(&parts, &body_bytes);
+    // The original bytes are kept for proxying, the decoded ones feed
+    // matching, templates and scripts
+    let decoded_bytes = match headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(encoding) => match compression::decode(encoding, &body_bytes) {
+            Ok(decoded) => decoded,
+            Err(compression::DecodeError::TooLarge) => {
+                tracing::warn!("Rejecting {} {}: decoded body is too large", method, path);
+                return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+            }
+            Err(e) => {
+                tracing::warn!("Cannot decode request body: {}", e);
+                body_bytes.to_vec()
+            }
+        },
+        None => body_bytes.to_vec(),
+    };
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    let body_json = serializers::decode_body(content_type, &decoded_bytes);
+    let mut ctx = RequestContext::new(
+        method,
+        &parts.uri,
+        headers,
+        &decoded_bytes,
+        body_json.clone(),
+    );
 
     tracing::info!("Incoming request: {} {}", method, path);
 
-    let expectations = state.expectations.load();
-    let matched = expectations
-        .iter()
-        .find(|exp| matcher::matches(method, path, headers, &body_json, &exp.condition));
+    let matched = expectations.iter().find(|exp| {
+        matcher::matches(
+            method,
+            path,
+            &ctx.query,
+            headers,
+            &body_json,
+            &exp.condition,
+        )
+    });
 
-    if let Some(_exp) = matched {
-        metrics::REQUEST_COUNTER
-            .with_label_values(&["true", path])
-            .inc();
-    } else {
-        metrics::REQUEST_COUNTER
-            .with_label_values(&["false", path])
-            .inc();
-    }
+    let fallback = match matched {
+        Some(_) => None,
+        None => state.fallback.load_full(),
+    };
+    let outcome = match (matched, &fallback) {
+        (Some(_), _) => "true",
+        (None, Some(_)) => "passthrough",
+        (None, None) => "false",
+    };
+    metrics::REQUEST_COUNTER
+        .with_label_values(&[outcome, path])
+        .inc();
+
+    // Roll jitter and sample latency up front so the delay can be logged
+    let jitter = matched.and_then(|exp| pick_jitter(&exp.response));
+    let latency_ms =
+        matched.and_then(|exp| exp.response.response.latency.as_ref().map(latency::sample));
+    let jitter_latency_ms = jitter.and_then(|j| j.response.latency.as_ref().map(latency::sample));
 
     let log_entry = LogEntry {
         timestamp: chrono::Utc::now().to_rfc3339(),
@@ -469,76 +703,163 @@ async fn handle_request(State(state): State<Arc<AppState>>, req: Request) -> Res
         body: body_json.clone(),
         matched: matched.is_some(),
         expectation_id: matched.map(|e| e.id),
+        latency_ms: match (latency_ms, jitter_latency_ms) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or(0) + b.unwrap_or(0)),
+        },
+        fault: match jitter {
+            Some(j) => j.response.fault,
+            None => matched.and_then(|exp| exp.response.response.fault),
+        },
+        error: None,
+        callback: None,
+        passthrough: fallback.is_some(),
     };
-    let _ = state.log_tx.send(log_entry);
+    let _ = state.log_tx.send(log_entry.clone());
 
-    if let Some(exp) = matched {
+    let response = if let Some(exp) = matched {
         tracing::info!("Matched expectation: {}", exp.id);
-
-        if let Some(ref script) = exp.response.script {
-            tracing::info!("Executing Lua script for mock {}", exp.id);
-            match execute_lua_script(script, method.as_str(), path, headers, &body_json).await {
-                Ok(res) => return res,
-                Err(e) => {
-                    tracing::error!("Lua execution failed: {}", e);
-                    return (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("Lua error: {}", e),
-                    )
-                        .into_response();
+        ctx.counter = templating::next_counter(exp.id);
+        ctx.strict_templates = exp
+            .response
+            .strict_templates
+            .unwrap_or(state.strict_templates);
+
+        // Set once the mock's own response is served, rather than an error
+        // or an injected failure, as only that triggers callbacks
+        let mut served = false;
+        let response = async {
+            if let Some(ref script) = exp.response.script {
+                tracing::info!("Executing Lua script for mock {}", exp.id);
+                match execute_lua_script(script, &ctx).await {
+                    Ok(res) => {
+                        served = true;
+                        return res;
+                    }
+                    Err(e) => {
+                        tracing::error!("Lua execution failed: {}", e);
+                        return (
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            format!("Lua error: {}", e),
+                        )
+                            .into_response();
+                    }
                 }
             }
-        }
 
-        if let Some(ref proxy_config) = exp.response.proxy {
-            tracing::info!("Proxying request to: {}", proxy_config.url);
-            return forward_to_upstream(
-                &state,
-                proxy_config,
-                parts.method,
-                parts.uri,
-                parts.headers,
-                body_bytes,
-            )
-            .await;
-        }
+            let proxy_config = exp.response.proxy.as_ref();
+            let latency_phase = proxy_config
+                .and_then(|p| p.latency_phase)
+                .unwrap_or_default();
+            if let Some(ms) = latency_ms
+                && (latency_phase == models::LatencyPhase::Before || jitter.is_some())
+            {
+                latency::delay(ms, path).await;
+            }
 
-        if let Some(latency) = exp.response.response.latency
-            && latency > 0
-        {
-            tracing::info!("Applying latency delay: {}ms", latency);
-            tokio::time::sleep(std::time::Duration::from_millis(latency)).await;
-        }
+            // Injected errors never reach the upstream
+            if let Some(jitter) = jitter {
+                return apply_jitter(jitter, jitter_latency_ms, &ctx, connection.as_ref())
+                    .await
+                    .unwrap_or_else(|e| template_error(&state, log_entry, e));
+            }
 
-        if let Some(jitter_res) = apply_jitter(&exp.response, path, &body_json, headers).await {
-            return jitter_res;
-        }
+            if let Some(proxy_config) = proxy_config {
+                let fault = exp.response.response.fault;
+                if let Some(fault) = fault
+                    && faults::replaces_response(fault)
+                {
+                    return faults::inject(fault, connection.as_ref(), Response::default()).await;
+                }
 
-        let status = StatusCode::from_u16(exp.response.response.status_code.unwrap_or(200))
-            .unwrap_or(StatusCode::OK);
-        let mut response_builder = Response::builder().status(status);
+                tracing::info!("Proxying request to: {}", proxy_config.urls().join(", "));
+                let outbound = Outbound {
+                    method: parts.method.clone(),
+                    uri: parts.uri.clone(),
+                    headers: parts.headers,
+                    body: body_bytes,
+                    stream,
+                    upgrade,
+                };
+                let response = forward_to_upstream(&state, proxy_config, outbound, &ctx).await;
+
+                if let Some(ms) = latency_ms
+                    && latency_phase == models::LatencyPhase::After
+                {
+                    latency::delay(ms, path).await;
+                }
+                if let Some(fault) = fault {
+                    return faults::inject(fault, connection.as_ref(), response).await;
+                }
+                served = true;
+                return response;
+            }
 
-        if let Some(ref res_headers) = exp.response.response.headers {
-            for (key, value) in res_headers {
-                response_builder = response_builder.header(key, value);
+            let mut response = match build_response(&exp.response.response, StatusCode::OK, &ctx) {
+                Ok(response) => response,
+                Err(e) => return template_error(&state, log_entry, e),
+            };
+            if let Some(ref shadow) = exp.response.shadow {
+                let (response_parts, body) = response.into_parts();
+                let body = body
+                    .collect()
+                    .await
+                    .map(|b| b.to_bytes())
+                    .unwrap_or_default();
+                let served = proxy::Message {
+                    status: Some(response_parts.status),
+                    headers: response_parts.headers.clone(),
+                    body: body.clone(),
+                };
+                let outbound = Outbound {
+                    method: method.clone(),
+                    uri: parts.uri.clone(),
+                    headers: parts.headers.clone(),
+                    body: body_bytes.clone(),
+                    stream: None,
+                    upgrade: None,
+                };
+                spawn_shadow(&state, exp.id, shadow, outbound, &ctx, served);
+                response = Response::from_parts(response_parts, Body::from(body));
             }
-        }
+            if let Some(fault) = exp.response.response.fault {
+                return faults::inject(fault, connection.as_ref(), response).await;
+            }
+            tracing::info!("Returning matched response: status={}", response.status());
+            served = true;
 
-        let response_body = build_response_body(
-            &exp.response.response,
-            path,
-            &body_json,
-            headers,
-            &mut response_builder,
+            metrics::REQUEST_DURATION
+                .with_label_values(&[path])
+                .observe(start.elapsed().as_secs_f64());
+            response
+        }
+        .await;
+
+        match exp.response.callbacks {
+            Some(ref callbacks) if served && !callbacks.is_empty() => callbacks::schedule(
+                response,
+                callbacks.clone(),
+                ctx,
+                exp.id,
+                state.proxy_client.clone(),
+                state.log_tx.clone(),
+            ),
+            _ => response,
+        }
+    } else if let Some(fallback) = fallback {
+        tracing::info!(
+            "No match found, passing through to {}",
+            fallback.urls().join(", ")
         );
-
-        let response = response_builder.body(response_body).unwrap();
-        tracing::info!("Returning matched response: status={}", response.status());
-
-        metrics::REQUEST_DURATION
-            .with_label_values(&[path])
-            .observe(start.elapsed().as_secs_f64());
-        response
+        let outbound = Outbound {
+            method: parts.method.clone(),
+            uri: parts.uri.clone(),
+            headers: parts.headers,
+            body: body_bytes,
+            stream,
+            upgrade,
+        };
+        forward_to_upstream(&state, &fallback, outbound, &ctx).await
     } else {
         tracing::warn!("No match found for {} {}", method, path);
         (
@@ -553,173 +874,628 @@ async fn handle_request(State(state): State<Arc<AppState>>, req: Request) -> Res
             })),
         )
             .into_response()
+    };
+
+    state
+        .journal
+        .record(journal_request, started, start, response)
+}
+
+/// Whether the request body can be streamed to a proxy instead of being
+/// read first. Only when no expectation looks at bodies, so the match is
+/// known up front, and the proxy that will serve it needs no body either.
+fn streams_request(
+    state: &AppState,
+    expectations: &[Expectation],
+    parts: &http::request::Parts,
+) -> bool {
+    if expectations.iter().any(|exp| exp.condition.body.is_some()) {
+        return false;
     }
+    let query = RequestContext::new(&parts.method, &parts.uri, &parts.headers, &[], None).query;
+    let matched = expectations.iter().find(|exp| {
+        matcher::matches(
+            &parts.method,
+            parts.uri.path(),
+            &query,
+            &parts.headers,
+            &None,
+            &exp.condition,
+        )
+    });
+    let proxy_config = match matched {
+        Some(exp) => {
+            let res = &exp.response;
+            if res.script.is_some()
+                || res.jitter.is_some()
+                || res.callbacks.as_ref().is_some_and(|c| !c.is_empty())
+            {
+                return false;
+            }
+            res.proxy.clone().map(Arc::new)
+        }
+        None => state.fallback.load_full(),
+    };
+    proxy_config.is_some_and(|p| proxy::streams_request(&p, state.record.as_ref()))
+}
+
+/// A request on its way to a proxy. The body has either been read already
+/// or is still arriving as `stream`.
+struct Outbound {
+    method: http::Method,
+    uri: http::Uri,
+    headers: HeaderMap,
+    body: Bytes,
+    stream: Option<Body>,
+    upgrade: Option<hyper::upgrade::OnUpgrade>,
 }
 
 async fn forward_to_upstream(
     state: &AppState,
     proxy_config: &models::ProxyConfig,
-    method: http::Method,
-    uri: http::Uri,
-    mut headers: HeaderMap,
-    body_bytes: Bytes,
+    outbound: Outbound,
+    ctx: &RequestContext,
 ) -> Response {
-    let path_and_query = uri
-        .path_and_query()
-        .map(|pq: &http::uri::PathAndQuery| pq.as_str())
-        .unwrap_or("");
-
-    let url = format!(
-        "{}{}",
-        proxy_config.url.trim_end_matches('/'),
-        path_and_query
-    );
+    let Outbound {
+        method,
+        uri,
+        mut headers,
+        body,
+        stream,
+        upgrade,
+    } = outbound;
+    proxy::upstream_headers(proxy_config, &mut headers, upgrade.is_some());
+
+    let mut outgoing = proxy::Message {
+        status: None,
+        headers,
+        body,
+    };
+    if let Some(transform) = proxy_config
+        .transform
+        .as_ref()
+        .and_then(|t| t.request.as_ref())
+        && let Err(e) = proxy::apply_transform(transform, &mut outgoing, ctx)
+    {
+        return transform_error(e);
+    }
 
-    // Overlay override headers
-    if let Some(ref overrides) = proxy_config.headers {
-        for (k, v) in overrides {
-            if let Ok(name) = header::HeaderName::from_bytes(k.as_bytes())
-                && let Ok(value) = header::HeaderValue::from_str(v)
-            {
-                headers.insert(name, value);
+    let mut stream = stream.map(|body| reqwest::Body::wrap_stream(body.into_data_stream()));
+    let buffer = upgrade.is_none() && proxy::buffers_response(proxy_config, state.record.as_ref());
+    // Targets that cannot be connected to are failed over to the next one
+    let targets = state.balancer.targets(proxy_config);
+    let mut attempt = 0;
+    let upstream = loop {
+        let base = targets[attempt];
+        let url = match proxy::target_url(proxy_config, base, &uri, ctx) {
+            Ok(url) => url,
+            Err(e) => {
+                tracing::error!("Cannot build proxy URL: {}", e);
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Template error: {}", e),
+                )
+                    .into_response();
+            }
+        };
+        let (client, url) = match state.proxy_clients.get(proxy_config, &url) {
+            Ok(client) => client,
+            Err(e) => {
+                tracing::error!("Cannot create proxy client: {}", e);
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Proxy client error: {}", e),
+                )
+                    .into_response();
+            }
+        };
+        tracing::debug!("Forwarding to {}", url);
+
+        let outcome = proxy::exchange(
+            &client,
+            proxy_config,
+            &method,
+            &url,
+            &outgoing,
+            stream.take(),
+            buffer,
+        )
+        .await;
+        state.balancer.report(
+            proxy_config,
+            base,
+            outcome
+                .as_ref()
+                .map_or(true, |upstream| upstream.status.is_server_error()),
+        );
+        match outcome {
+            Ok(upstream) => break upstream,
+            Err(failure) if attempt + 1 < targets.len() && proxy::fails_over(failure.kind) => {
+                let kind = proxy::failure_name(failure.kind);
+                tracing::warn!("Upstream {} failed ({}), failing over", url, kind);
+                metrics::UPSTREAM_FAILURES.with_label_values(&[kind]).inc();
+                attempt += 1;
             }
+            Err(failure) => return upstream_failure(proxy_config, failure, ctx),
+        }
+    };
+
+    let mut incoming = proxy::Message {
+        status: Some(upstream.status),
+        headers: upstream.headers,
+        body: Bytes::new(),
+    };
+    let streamed = match upstream.body {
+        proxy::ResponseBody::Buffered(body) => {
+            incoming.body = body;
+            None
         }
+        proxy::ResponseBody::Streamed(response) => Some(response),
+    };
+    // Streamed bodies pass untouched, only their status and headers change
+    if let Some(transform) = proxy_config
+        .transform
+        .as_ref()
+        .and_then(|t| t.response.as_ref())
+        && let Err(e) = proxy::apply_transform(transform, &mut incoming, ctx)
+    {
+        return transform_error(e);
     }
+    let status = incoming.status.unwrap_or(StatusCode::OK);
 
-    let mut proxy_req = state.proxy_client.request(method, url).headers(headers);
+    let body = match streamed {
+        Some(response) if status == StatusCode::SWITCHING_PROTOCOLS => {
+            if let Some(upgrade) = upgrade {
+                proxy::tunnel(upgrade, response);
+            }
+            Body::empty()
+        }
+        Some(response) => Body::from_stream(response.bytes_stream()),
+        None => {
+            // Recordings replay what the client got, transforms included
+            if let Some(record) = proxy_config.record.as_ref().or(state.record.as_ref())
+                && let Some(recorded) = recording::to_expectation(
+                    record,
+                    ctx,
+                    status,
+                    &incoming.headers,
+                    &incoming.body,
+                )
+            {
+                record_expectation(state, recorded).await;
+            }
+            Body::from(incoming.body)
+        }
+    };
 
-    if !body_bytes.is_empty() {
-        proxy_req = proxy_req.body(body_bytes);
-    }
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    *response.headers_mut() = incoming.headers;
+    response
+}
 
-    match proxy_req.send().await {
-        Ok(res) => {
-            let status = StatusCode::from_u16(res.status().as_u16()).unwrap_or(StatusCode::OK);
-            let mut response_builder = Response::builder().status(status);
+/// Sends a request a mock has served to its shadow upstream in the
+/// background and compares the answer with the mock's response.
+fn spawn_shadow(
+    state: &Arc<AppState>,
+    expectation_id: u64,
+    config: &models::ShadowConfig,
+    outbound: Outbound,
+    ctx: &RequestContext,
+    served: proxy::Message,
+) {
+    let state = state.clone();
+    let config = config.clone();
+    let ctx = ctx.clone();
+    tokio::spawn(async move {
+        let method = outbound.method.clone();
+        let path = outbound.uri.path().to_string();
+        let upstream = shadow_upstream(&state, &config.upstream, outbound, &ctx).await;
+        state
+            .drift
+            .record(expectation_id, &method, &path, &config, &served, upstream);
+    });
+}
+
+/// Calls a shadow upstream like a proxy would, without failover, retries
+/// on other targets or recording, and reads the whole response.
+async fn shadow_upstream(
+    state: &AppState,
+    proxy_config: &models::ProxyConfig,
+    outbound: Outbound,
+    ctx: &RequestContext,
+) -> Result<proxy::Message, String> {
+    let mut headers = outbound.headers;
+    proxy::upstream_headers(proxy_config, &mut headers, false);
+    let mut outgoing = proxy::Message {
+        status: None,
+        headers,
+        body: outbound.body,
+    };
+    if let Some(transform) = proxy_config
+        .transform
+        .as_ref()
+        .and_then(|t| t.request.as_ref())
+    {
+        proxy::apply_transform(transform, &mut outgoing, ctx)?;
+    }
 
-            for (name, value) in res.headers() {
-                response_builder = response_builder.header(name, value);
+    let base = state.balancer.first_target(proxy_config);
+    let url = proxy::target_url(proxy_config, base, &outbound.uri, ctx)?;
+    let (client, url) = state.proxy_clients.get(proxy_config, &url)?;
+    let upstream = proxy::exchange(
+        &client,
+        proxy_config,
+        &outbound.method,
+        &url,
+        &outgoing,
+        None,
+        true,
+    )
+    .await
+    .map_err(|failure| {
+        format!(
+            "upstream failed ({}): {}",
+            proxy::failure_name(failure.kind),
+            failure.error
+        )
+    })?;
+    state
+        .balancer
+        .report(proxy_config, base, upstream.status.is_server_error());
+
+    let mut incoming = proxy::Message {
+        status: Some(upstream.status),
+        headers: upstream.headers,
+        body: match upstream.body {
+            proxy::ResponseBody::Buffered(body) => body,
+            proxy::ResponseBody::Streamed(response) => {
+                response.bytes().await.map_err(|e| e.to_string())?
             }
+        },
+    };
+    if let Some(transform) = proxy_config
+        .transform
+        .as_ref()
+        .and_then(|t| t.response.as_ref())
+    {
+        proxy::apply_transform(transform, &mut incoming, ctx)?;
+    }
+    Ok(incoming)
+}
 
-            let body_bytes = match res.bytes().await {
-                Ok(b) => b,
-                Err(e) => {
-                    tracing::error!("Failed to read upstream response body: {}", e);
-                    return (
-                        StatusCode::BAD_GATEWAY,
-                        "Failed to read upstream response body",
-                    )
-                        .into_response();
-                }
-            };
+/// Answers for an upstream that could not be reached, with the response
+/// configured for the kind of failure or a 502 (504 for timeouts).
+fn upstream_failure(
+    proxy_config: &models::ProxyConfig,
+    failure: proxy::Failure,
+    ctx: &RequestContext,
+) -> Response {
+    let kind = proxy::failure_name(failure.kind);
+    tracing::error!("Proxy request failed ({}): {}", kind, failure.error);
+    metrics::UPSTREAM_FAILURES.with_label_values(&[kind]).inc();
 
-            response_builder.body(Body::from(body_bytes)).unwrap()
-        }
-        Err(e) => {
-            tracing::error!("Proxy request failed: {}", e);
+    let default_status = match failure.kind {
+        models::UpstreamFailure::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::BAD_GATEWAY,
+    };
+    match proxy_config
+        .errors
+        .as_ref()
+        .and_then(|errors| errors.get(&failure.kind))
+    {
+        Some(res_config) => build_response(res_config, default_status, ctx).unwrap_or_else(|e| {
+            tracing::error!("Template error: {}", e);
             (
-                StatusCode::BAD_GATEWAY,
-                format!("Proxy request failed: {}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Template error: {}", e),
             )
                 .into_response()
+        }),
+        None => (
+            default_status,
+            format!("Proxy request failed: {}", failure.error),
+        )
+            .into_response(),
+    }
+}
+
+fn transform_error(error: String) -> Response {
+    tracing::error!("Proxy transform failed: {}", error);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Transform error: {}", error),
+    )
+        .into_response()
+}
+
+/// Appends a recorded expectation unless one with the same condition exists.
+async fn record_expectation(state: &AppState, mut recorded: Expectation) {
+    let mut added = false;
+    state.expectations.rcu(|mocks| {
+        let mut mocks = (**mocks).clone();
+        added = !recording::is_duplicate(&mocks, &recorded);
+        if added {
+            recorded.id = mocks.iter().map(|m| m.id).max().unwrap_or(0) + 1;
+            mocks.push(recorded.clone());
         }
+        mocks
+    });
+    if added {
+        tracing::info!(
+            "Recorded expectation {} for {} {}",
+            recorded.id,
+            recorded.condition.method.as_deref().unwrap_or(""),
+            recorded.condition.path.as_deref().unwrap_or("")
+        );
+        persist_expectations(state, &state.expectations.load()).await;
     }
 }
 
+/// Picks at most one jitter outcome with a single draw. If the outcome
+/// probabilities add up to more than 1 they are treated as relative weights.
+fn pick_jitter(res_config: &models::MockResponse) -> Option<&models::JitterConfig> {
+    let outcomes = res_config.jitter.as_ref()?.outcomes();
+    let total: f64 = outcomes.iter().map(|j| j.probability.max(0.0)).sum();
+    let random: f64 = rng::with_rng(|rng| rng.random());
+    let mut draw = random * total.max(1.0);
+
+    for jitter in outcomes {
+        let probability = jitter.probability.max(0.0);
+        if draw < probability {
+            return Some(jitter);
+        }
+        draw -= probability;
+    }
+    None
+}
+
 async fn apply_jitter(
-    res_config: &models::MockResponse,
-    path: &str,
-    body_json: &Option<Value>,
-    request_headers: &header::HeaderMap,
-) -> Option<Response> {
-    let jitter = res_config.jitter.as_ref()?;
-    let random: f64 = rand::random();
-
-    if random < jitter.probability {
-        tracing::info!("Jitter matched! Returning error response");
-
-        if let Some(latency) = jitter.response.latency
-            && latency > 0
-        {
-            tracing::info!("Applying jitter latency delay: {}ms", latency);
-            tokio::time::sleep(std::time::Duration::from_millis(latency)).await;
+    jitter: &models::JitterConfig,
+    latency_ms: Option<u64>,
+    ctx: &RequestContext,
+    connection: Option<&faults::ConnectionHandle>,
+) -> Result<Response, String> {
+    tracing::info!("Jitter matched! Returning error response");
+
+    if let Some(ms) = latency_ms {
+        latency::delay(ms, &ctx.path).await;
+    }
+
+    let response = build_response(&jitter.response, StatusCode::INTERNAL_SERVER_ERROR, ctx)?;
+    if let Some(fault) = jitter.response.fault {
+        return Ok(faults::inject(fault, connection, response).await);
+    }
+    Ok(response)
+}
+
+/// Reports a template that failed to render on the log stream and turns it
+/// into a 500.
+fn template_error(state: &AppState, log_entry: LogEntry, error: String) -> Response {
+    tracing::error!("Template error: {}", error);
+    let _ = state.log_tx.send(LogEntry {
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        error: Some(format!("Template error: {}", error)),
+        ..log_entry
+    });
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Template error: {}", error),
+    )
+        .into_response()
+}
+
+fn build_response(
+    res_config: &models::ResponseConfig,
+    default_status: StatusCode,
+    ctx: &RequestContext,
+) -> Result<Response, String> {
+    let mut builder = build_response_head(res_config, default_status, ctx)?;
+    let body = build_response_body(res_config, ctx, &mut builder)?;
+    let body = compress_response_body(res_config, ctx, &mut builder, body);
+    builder
+        .body(Body::from(body))
+        .map_err(|e| format!("invalid response: {}", e))
+}
+
+/// Compresses the body as configured or as negotiated through
+/// `Accept-Encoding`. A `Content-Encoding` header set on the mock is left
+/// alone.
+fn compress_response_body(
+    res_config: &models::ResponseConfig,
+    ctx: &RequestContext,
+    response_builder: &mut axum::http::response::Builder,
+    body: Vec<u8>,
+) -> Vec<u8> {
+    let has_encoding = response_builder
+        .headers_ref()
+        .map(|h| h.contains_key(header::CONTENT_ENCODING))
+        .unwrap_or(false);
+    if has_encoding {
+        return body;
+    }
+
+    let mut b = Response::builder();
+    std::mem::swap(response_builder, &mut b);
+    let encoding = match res_config.compression.unwrap_or_default() {
+        models::Compression::Auto => {
+            b = b.header(header::VARY, "Accept-Encoding");
+            ctx.headers
+                .get(header::ACCEPT_ENCODING.as_str())
+                .and_then(|accept| compression::negotiate(accept))
         }
+        models::Compression::None => None,
+        models::Compression::Gzip => Some("gzip"),
+        models::Compression::Br => Some("br"),
+        models::Compression::Zstd => Some("zstd"),
+        models::Compression::Deflate => Some("deflate"),
+    };
+
+    let (encoding, body) = match encoding {
+        Some(encoding) if !body.is_empty() => match compression::encode(encoding, &body) {
+            Ok(compressed) => (Some(encoding), compressed),
+            Err(e) => {
+                tracing::warn!("Cannot compress response with {}: {}", encoding, e);
+                (None, body)
+            }
+        },
+        _ => (None, body),
+    };
 
-        let status = StatusCode::from_u16(jitter.response.status_code.unwrap_or(500))
-            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
-        let mut response_builder = Response::builder().status(status);
+    let declared = res_config.declared_encoding.as_deref().or(encoding);
+    if let Some(declared) = declared {
+        b = b.header(header::CONTENT_ENCODING, declared);
+    }
+    *response_builder = b;
+    body
+}
 
-        if let Some(ref res_headers) = jitter.response.headers {
-            for (key, value) in res_headers {
-                response_builder = response_builder.header(key, value);
+/// Resolves the (possibly templated) status code and header values.
+fn build_response_head(
+    res_config: &models::ResponseConfig,
+    default_status: StatusCode,
+    ctx: &RequestContext,
+) -> Result<axum::http::response::Builder, String> {
+    let status = match res_config.status_code {
+        None => default_status,
+        Some(models::ResponseStatus::Code(code)) => {
+            StatusCode::from_u16(code).unwrap_or(default_status)
+        }
+        Some(models::ResponseStatus::Template(ref template)) => {
+            let resolved =
+                templating::resolve_template_value(Value::String(template.clone()), ctx)?;
+            let code = match resolved {
+                Value::Number(ref n) => n.as_u64(),
+                Value::String(ref s) => s.trim().parse().ok(),
+                _ => None,
+            };
+            match code.and_then(|c| u16::try_from(c).ok()) {
+                Some(c) => StatusCode::from_u16(c).unwrap_or(default_status),
+                None => {
+                    tracing::warn!(
+                        "Status template {} resolved to {}, using {}",
+                        template,
+                        resolved,
+                        default_status
+                    );
+                    default_status
+                }
             }
         }
+    };
+    let mut response_builder = Response::builder().status(status);
 
-        let body = build_response_body(
-            &jitter.response,
-            path,
-            body_json,
-            request_headers,
-            &mut response_builder,
-        );
-
-        return Some(response_builder.body(body).unwrap());
+    if let Some(ref res_headers) = res_config.headers {
+        for (key, value) in res_headers {
+            let Ok(name) = header::HeaderName::from_bytes(key.as_bytes()) else {
+                tracing::warn!("Skipping header with invalid name {:?}", key);
+                continue;
+            };
+            let value = templating::resolve_template(value, ctx)?;
+            match header::HeaderValue::from_str(&value) {
+                Ok(value) => response_builder = response_builder.header(name, value),
+                Err(_) => tracing::warn!("Skipping header {} with invalid value {:?}", key, value),
+            }
+        }
     }
-    None
+    Ok(response_builder)
 }
 
 fn build_response_body(
     res_config: &models::ResponseConfig,
-    path: &str,
-    req_body: &Option<Value>,
-    request_headers: &header::HeaderMap,
+    ctx: &RequestContext,
     response_builder: &mut axum::http::response::Builder,
-) -> Body {
+) -> Result<Vec<u8>, String> {
     let Some(ref res_body) = res_config.body else {
-        return Body::empty();
+        return Ok(Vec::new());
     };
 
-    let path_segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
-    let resolved_val =
-        templating::resolve_template_value(res_body.clone(), &path_segments, req_body);
-    let resolved_body = serde_json::to_string(&resolved_val).unwrap();
+    // Full templates are rendered by the block-capable engine instead
+    if res_config.body_type.as_deref() == Some("template") {
+        return handle_template_response(res_body, ctx, response_builder);
+    }
+
+    let resolved_val = templating::resolve_template_value(res_body.clone(), ctx)?;
 
     // Handle Non-JSON (Text/HTML) body type
     if let Some(ref b_type) = res_config.body_type
         && b_type == "text"
     {
-        return handle_text_response(resolved_body, response_builder);
+        let resolved_body = serde_json::to_string(&resolved_val).unwrap();
+        return Ok(handle_text_response(resolved_body, response_builder));
     }
 
-    let accept_bson = request_headers
-        .get(header::ACCEPT)
-        .and_then(|h| h.to_str().ok())
-        .map(|s| s.contains("application/bson"))
-        .unwrap_or(false);
+    Ok(handle_serialized_response(
+        &resolved_val,
+        res_config,
+        ctx,
+        response_builder,
+    ))
+}
 
-    if accept_bson && let Some(body) = handle_bson_response(&resolved_body, response_builder) {
-        return body;
-    }
+/// Serializes the body in the `body_type` format, or in the format the
+/// client prefers when negotiation is enabled.
+fn handle_serialized_response(
+    resolved_val: &Value,
+    res_config: &models::ResponseConfig,
+    ctx: &RequestContext,
+    response_builder: &mut axum::http::response::Builder,
+) -> Vec<u8> {
+    let preferred = match res_config.body_type.as_deref() {
+        Some(name) => serializers::by_name(name).unwrap_or_else(|| {
+            tracing::warn!("Unknown body_type {}, using json", name);
+            serializers::json()
+        }),
+        None => serializers::json(),
+    };
+
+    let negotiate = res_config.negotiate.unwrap_or(true);
+    let serializer = match ctx.headers.get(header::ACCEPT.as_str()) {
+        Some(accept) if negotiate => serializers::negotiate(accept, preferred).unwrap_or(preferred),
+        _ => preferred,
+    };
+
+    let (serializer, bytes) = match (serializer.serialize)(resolved_val) {
+        Ok(bytes) => (serializer, bytes),
+        Err(e) => {
+            tracing::warn!(
+                "Cannot serialize body as {}: {}, using json",
+                serializer.name,
+                e
+            );
+            let json = serializers::json();
+            (json, (json.serialize)(resolved_val).unwrap_or_default())
+        }
+    };
 
-    // Default to JSON
     let mut b = Response::builder();
     std::mem::swap(response_builder, &mut b);
-    if !b
+    if negotiate {
+        b = b.header(header::VARY, "Accept");
+    }
+    // A configured Content-Type only applies to the mock's own format
+    let has_content_type = b
         .headers_ref()
         .map(|h| h.contains_key(header::CONTENT_TYPE))
-        .unwrap_or(false)
+        .unwrap_or(false);
+    if !has_content_type {
+        b = b.header(header::CONTENT_TYPE, serializer.content_type);
+    } else if !std::ptr::eq(serializer, preferred)
+        && let Some(headers) = b.headers_mut()
     {
-        *response_builder = b.header(header::CONTENT_TYPE, "application/json");
-    } else {
-        *response_builder = b;
+        headers.insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static(serializer.content_type),
+        );
     }
-    Body::from(resolved_body)
+    *response_builder = b;
+    bytes
 }
 
 fn handle_text_response(
     resolved_body: String,
     response_builder: &mut axum::http::response::Builder,
-) -> Body {
+) -> Vec<u8> {
     // If it's stored as a JSON string, extract the raw content
     let raw_body = serde_json::from_str::<Value>(&resolved_body)
         .ok()
@@ -739,31 +1515,38 @@ fn handle_text_response(
     } else {
         *response_builder = b;
     }
-    Body::from(raw_body)
+    raw_body.into_bytes()
 }
 
-fn handle_bson_response(
-    resolved_body: &str,
+fn handle_template_response(
+    template: &Value,
+    ctx: &RequestContext,
     response_builder: &mut axum::http::response::Builder,
-) -> Option<Body> {
-    let val: Value = serde_json::from_str(resolved_body).unwrap_or(Value::Null);
-    if let Ok(bson_val) = bson::to_bson(&val) {
-        let mut bytes = Vec::new();
-        if let Some(doc) = bson_val.as_document() {
-            doc.to_writer(&mut bytes).unwrap();
-        } else if let bson::Bson::Array(arr) = bson_val {
-            let doc = bson::doc! { "data": arr };
-            doc.to_writer(&mut bytes).unwrap();
-        } else {
-            return None;
-        }
+) -> Result<Vec<u8>, String> {
+    let template = match template {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+    let rendered = templating::render_template(&template, ctx)?;
 
-        let mut b = Response::builder();
-        std::mem::swap(response_builder, &mut b);
-        *response_builder = b.header(header::CONTENT_TYPE, "application/bson");
-        return Some(Body::from(bytes));
+    // Default to JSON when the output is valid JSON, text otherwise
+    let mut b = Response::builder();
+    std::mem::swap(response_builder, &mut b);
+    if !b
+        .headers_ref()
+        .map(|h| h.contains_key(header::CONTENT_TYPE))
+        .unwrap_or(false)
+    {
+        let content_type = if serde_json::from_str::<Value>(&rendered).is_ok() {
+            "application/json"
+        } else {
+            "text/plain"
+        };
+        *response_builder = b.header(header::CONTENT_TYPE, content_type);
+    } else {
+        *response_builder = b;
     }
-    None
+    Ok(rendered.into_bytes())
 }
 
 async fn metrics_handler() -> impl IntoResponse {
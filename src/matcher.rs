@@ -1,10 +1,12 @@
 use crate::models::RequestCondition;
 use axum::http::{HeaderMap, Method};
 use serde_json::Value;
+use std::collections::HashMap;
 
 pub fn matches(
     method: &Method,
     path: &str,
+    query: &HashMap<String, String>,
     headers: &HeaderMap,
     body: &Option<Value>,
     condition: &RequestCondition,
@@ -25,6 +27,21 @@ pub fn matches(
         return false;
     }
 
+    // Match query parameters
+    if let Some(ref cond_query) = condition.query {
+        for (key, value) in cond_query {
+            if query.get(key) != Some(value) {
+                tracing::trace!(
+                    "Query mismatch for {}: expected {}, got {:?}",
+                    key,
+                    value,
+                    query.get(key)
+                );
+                return false;
+            }
+        }
+    }
+
     // Match headers
     if let Some(ref cond_headers) = condition.headers {
         for (key, value) in cond_headers {
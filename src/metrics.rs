@@ -22,6 +22,58 @@ lazy_static! {
         REGISTRY
     )
     .unwrap();
+    pub static ref FAULTS_INJECTED: CounterVec = register_counter_vec_with_registry!(
+        opts!(
+            "mimicrab_faults_injected_total",
+            "Total number of connection-level faults injected"
+        ),
+        &["kind"],
+        REGISTRY
+    )
+    .unwrap();
+    pub static ref UPSTREAM_FAILURES: CounterVec = register_counter_vec_with_registry!(
+        opts!(
+            "mimicrab_upstream_failures_total",
+            "Total number of proxied requests that could not reach the upstream"
+        ),
+        &["kind"],
+        REGISTRY
+    )
+    .unwrap();
+    pub static ref UPSTREAM_RETRIES: CounterVec = register_counter_vec_with_registry!(
+        opts!(
+            "mimicrab_upstream_retries_total",
+            "Total number of retried calls to upstreams"
+        ),
+        &["kind"],
+        REGISTRY
+    )
+    .unwrap();
+    pub static ref SHADOW_COMPARISONS: CounterVec = register_counter_vec_with_registry!(
+        opts!(
+            "mimicrab_shadow_comparisons_total",
+            "Total number of mock responses compared with their shadow upstream"
+        ),
+        &["expectation", "result"],
+        REGISTRY
+    )
+    .unwrap();
+    pub static ref UPSTREAM_EJECTIONS: CounterVec = register_counter_vec_with_registry!(
+        opts!(
+            "mimicrab_upstream_ejections_total",
+            "Total number of times a failing upstream target was ejected"
+        ),
+        &["upstream"],
+        REGISTRY
+    )
+    .unwrap();
+    pub static ref INJECTED_LATENCY: HistogramVec = register_histogram_vec_with_registry!(
+        "mimicrab_injected_latency_seconds",
+        "Histogram of latency injected into mock responses in seconds",
+        &["path"],
+        REGISTRY
+    )
+    .unwrap();
 }
 
 pub fn register_process_metrics() {
@@ -5,17 +5,92 @@ use std::collections::HashMap;
 pub struct RequestCondition {
     pub method: Option<String>,
     pub path: Option<String>,
+    pub query: Option<HashMap<String, String>>,
     pub headers: Option<HashMap<String, String>>,
     pub body: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ResponseConfig {
-    pub status_code: Option<u16>,
+    pub status_code: Option<ResponseStatus>,
     pub headers: Option<HashMap<String, String>>,
     pub body: Option<serde_json::Value>,
     pub body_type: Option<String>,
-    pub latency: Option<u64>,
+    pub latency: Option<Latency>,
+    pub fault: Option<Fault>,
+    /// Set to false to always answer in the `body_type` format, whatever
+    /// the client accepts.
+    pub negotiate: Option<bool>,
+    pub compression: Option<Compression>,
+    /// Sent as `Content-Encoding` instead of the encoding actually used, to
+    /// test how clients handle bodies they cannot decode.
+    pub declared_encoding: Option<String>,
+}
+
+/// How the response body is compressed. `auto` follows `Accept-Encoding`,
+/// the encodings force that compression whatever the client accepts.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    Auto,
+    None,
+    Gzip,
+    Br,
+    Zstd,
+    Deflate,
+}
+
+/// Connection-level failure injected instead of a well-formed response.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    ConnectionReset,
+    Hang,
+    CloseAfterHeaders,
+    TruncatedBody,
+    MalformedChunk,
+    Garbage,
+}
+
+/// A fixed status code or a template such as `{{body.expectedStatus:int}}`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum ResponseStatus {
+    Code(u16),
+    Template(String),
+}
+
+/// Delay in milliseconds applied before a response is returned. A plain
+/// number is a fixed delay, the object forms describe a distribution that
+/// is sampled once per request.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Latency {
+    Fixed(u64),
+    Uniform {
+        min: u64,
+        max: u64,
+    },
+    Normal {
+        mean: f64,
+        stddev: f64,
+        #[serde(default)]
+        distribution: LatencyDistribution,
+    },
+    Percentiles {
+        p50: u64,
+        p95: u64,
+        p99: u64,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LatencyDistribution {
+    #[default]
+    Normal,
+    LogNormal,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -25,19 +100,286 @@ pub struct JitterConfig {
     pub response: ResponseConfig,
 }
 
+/// Either a single jitter outcome or a list of weighted outcomes. Outcome
+/// probabilities are absolute, whatever is left over serves the normal
+/// response.
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Jitter {
+    Single(Box<JitterConfig>),
+    Weighted(Vec<JitterConfig>),
+}
+
+impl Jitter {
+    pub fn outcomes(&self) -> &[JitterConfig] {
+        match self {
+            Jitter::Single(jitter) => std::slice::from_ref(&**jitter),
+            Jitter::Weighted(outcomes) => outcomes,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct ProxyConfig {
+    /// Upstream base URL, may contain templates. Not needed with
+    /// `upstreams`
+    #[serde(default)]
     pub url: String,
+    /// Targets to balance requests between instead of `url`
+    pub upstreams: Option<Vec<Upstream>>,
+    /// How a target is picked from `upstreams`, defaults to round robin
+    pub balance: Option<Balance>,
+    /// When failing targets are taken out of `upstreams` for a while
+    pub ejection: Option<Ejection>,
     pub headers: Option<HashMap<String, String>>,
+    /// Turns proxied exchanges into expectations, overriding `--record`
+    pub record: Option<RecordConfig>,
+    /// Removed from the start of the path before it is forwarded
+    pub strip_prefix: Option<String>,
+    /// Path rewrites, the first rule that matches applies
+    pub rewrite: Option<Vec<RewriteRule>>,
+    /// Whether the (rewritten) path and query are appended to `url`.
+    /// Defaults to true
+    pub append_path: Option<bool>,
+    /// Host header sent upstream, defaults to preserving the incoming one
+    pub host: Option<HostHeader>,
+    pub transform: Option<ProxyTransform>,
+    /// Whether the mock's latency is added before the upstream is called
+    /// or after its response arrived. Defaults to before
+    pub latency_phase: Option<LatencyPhase>,
+    pub timeouts: Option<ProxyTimeouts>,
+    pub tls: Option<ProxyTls>,
+    /// Retries idempotent requests
+    pub retry: Option<RetryPolicy>,
+    /// Responses returned when the upstream cannot be reached, instead of
+    /// the default 502 or 504
+    pub errors: Option<HashMap<UpstreamFailure, ResponseConfig>>,
+}
+
+impl ProxyConfig {
+    /// Base URLs of all targets of the proxy.
+    pub fn urls(&self) -> Vec<&str> {
+        match self.upstreams {
+            Some(ref upstreams) if !upstreams.is_empty() => {
+                upstreams.iter().map(|u| u.url.as_str()).collect()
+            }
+            _ => vec![self.url.as_str()],
+        }
+    }
+}
+
+/// One of several targets of a proxy.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Upstream {
+    /// Base URL, may contain templates
+    pub url: String,
+    /// Share of requests relative to the other targets, used by weighted
+    /// and random balancing
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    Weighted,
+    Random,
+}
+
+/// Passive health tracking: a target that fails this many times in a row
+/// is skipped for `eject_ms`. Failures are connection errors, timeouts
+/// and 5xx responses.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Ejection {
+    #[serde(default = "default_consecutive_failures")]
+    pub consecutive_failures: u32,
+    #[serde(default = "default_eject_ms")]
+    pub eject_ms: u64,
+}
+
+impl Default for Ejection {
+    fn default() -> Self {
+        Self {
+            consecutive_failures: default_consecutive_failures(),
+            eject_ms: default_eject_ms(),
+        }
+    }
+}
+
+fn default_consecutive_failures() -> u32 {
+    3
+}
+
+fn default_eject_ms() -> u64 {
+    30_000
+}
+
+/// Timeouts in milliseconds for calls to an upstream.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ProxyTimeouts {
+    /// Establishing the connection
+    pub connect_ms: Option<u64>,
+    /// Waiting for the next read from the upstream
+    pub read_ms: Option<u64>,
+    /// The whole exchange, including the response body
+    pub total_ms: Option<u64>,
+}
+
+/// TLS settings for HTTPS upstreams. Certificates and keys are PEM files,
+/// e.g. from a mounted Kubernetes secret.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ProxyTls {
+    /// CA certificates trusted in addition to the system ones
+    pub ca_files: Option<Vec<String>>,
+    /// Client certificate (chain) for mutual TLS
+    pub cert_file: Option<String>,
+    /// Private key of `cert_file`
+    pub key_file: Option<String>,
+    /// Directory a `kubernetes.io/tls` secret is mounted at. Its `ca.crt`,
+    /// `tls.crt` and `tls.key` are used where the fields above are not set
+    pub secret_dir: Option<String>,
+    /// Name sent for SNI and verified against the certificate instead of
+    /// the host of the URL
+    pub server_name: Option<String>,
+    /// Accepts any certificate, for upstreams with self-signed ones
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+/// Ways in which calling an upstream can fail.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamFailure {
+    Timeout,
+    Dns,
+    Refused,
+    /// Other failures to connect, e.g. TLS errors
+    Connect,
+    /// Failures after connecting, e.g. a connection closed mid-response
+    Other,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LatencyPhase {
+    #[default]
+    Before,
+    After,
+}
+
+/// Changes made to the request sent upstream and to the response returned
+/// from it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProxyTransform {
+    pub request: Option<Transform>,
+    pub response: Option<Transform>,
+}
+
+/// Edits to a proxied message, applied in the order of the fields.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Transform {
+    /// Replaces the status of a response
+    pub status_code: Option<u16>,
+    pub remove_headers: Option<Vec<String>>,
+    /// Headers to set, replacing existing values
+    pub headers: Option<HashMap<String, String>>,
+    /// RFC 6902 JSON Patch applied to the body
+    pub json_patch: Option<json_patch::Patch>,
+    /// RFC 7396 JSON Merge Patch applied to the body
+    pub merge_patch: Option<serde_json::Value>,
+    /// Lua script that receives the message as `message` and returns it
+    pub script: Option<String>,
+}
+
+/// Rewrites a path with a regex. The replacement can refer to capture
+/// groups as `$1` or `${name}`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RewriteRule {
+    pub pattern: String,
+    pub replacement: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HostHeader {
+    /// Forward the Host header of the incoming request
+    #[default]
+    Preserve,
+    /// Use the host of the upstream URL
+    Upstream,
+}
+
+/// What a recorded expectation matches on besides method and path.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RecordConfig {
+    #[serde(default)]
+    pub query: bool,
+    #[serde(default)]
+    pub body: bool,
+    /// Request headers to match on
+    #[serde(default)]
+    pub headers: Vec<String>,
+}
+
+/// Request sent to another service once a mock response has been served.
+/// The URL, header values and body are templates with access to the
+/// original request.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Callback {
+    pub url: String,
+    /// Defaults to POST
+    pub method: Option<String>,
+    pub headers: Option<HashMap<String, String>>,
+    pub body: Option<serde_json::Value>,
+    /// Wait before sending, counted from when the response was sent
+    pub delay: Option<Latency>,
+    pub retry: Option<RetryPolicy>,
+}
+
+/// Retries on connection errors, 429 and 5xx responses, doubling the
+/// backoff after every attempt.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_ms: u64,
+}
+
+fn default_backoff_ms() -> u64 {
+    500
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MockResponse {
     #[serde(flatten)]
     pub response: ResponseConfig,
-    pub jitter: Option<JitterConfig>,
+    pub jitter: Option<Jitter>,
     pub proxy: Option<ProxyConfig>,
     pub script: Option<String>,
+    /// Overrides the global `--strict-templates` setting for this mock.
+    pub strict_templates: Option<bool>,
+    pub callbacks: Option<Vec<Callback>>,
+    pub shadow: Option<ShadowConfig>,
+}
+
+/// Sends requests served by a mock to the real upstream as well, in the
+/// background, and reports where its response differs from the mock's.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ShadowConfig {
+    pub upstream: ProxyConfig,
+    /// Response headers to compare besides the status and body
+    #[serde(default)]
+    pub headers: Vec<String>,
+    /// JSON pointers of body fields left out of the comparison, with `*`
+    /// matching any key or index, e.g. `/items/*/updated_at`
+    #[serde(default)]
+    pub ignore: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
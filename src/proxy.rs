@@ -0,0 +1,925 @@
+use crate::context::RequestContext;
+use crate::models::{
+    Balance, HostHeader, ProxyConfig, ProxyTls, RecordConfig, Transform, Upstream, UpstreamFailure,
+};
+use crate::{compression, metrics, rng, serializers, templating};
+use axum::body::Bytes;
+use axum::http::{HeaderMap, Method, StatusCode, Uri, header};
+use mlua::{Lua, LuaSerdeExt, Table, Value as LuaValue};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use regex::Regex;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Works out where a request is forwarded to: the templated base URL of
+/// the target, followed by the path with the prefix stripped and the first
+/// matching rewrite rule applied, and the original query.
+pub fn target_url(
+    config: &ProxyConfig,
+    base: &str,
+    uri: &Uri,
+    ctx: &RequestContext,
+) -> Result<String, String> {
+    let base = templating::resolve_template(base, ctx)?;
+    if !config.append_path.unwrap_or(true) {
+        return Ok(base);
+    }
+
+    let mut path = uri.path().to_string();
+    if let Some(ref prefix) = config.strip_prefix {
+        path = strip_prefix(&path, prefix).to_string();
+    }
+    for rule in config.rewrite.iter().flatten() {
+        let re = rewrite_regex(&rule.pattern)?;
+        if re.is_match(&path) {
+            path = re.replace(&path, rule.replacement.as_str()).into_owned();
+            break;
+        }
+    }
+
+    let mut url = format!("{}{}", base.trim_end_matches('/'), path);
+    if let Some(query) = uri.query() {
+        url.push('?');
+        url.push_str(query);
+    }
+    Ok(url)
+}
+
+/// Most rewrite patterns kept compiled. Patterns of replaced or deleted
+/// mocks stay until the cache fills up, and it then starts over with the
+/// patterns proxied requests still use.
+const MAX_REWRITE_PATTERNS: usize = 256;
+
+// Rewrite patterns compiled so far, as every proxied request applies them
+static REWRITE_PATTERNS: Lazy<Mutex<HashMap<String, Regex>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn rewrite_regex(pattern: &str) -> Result<Regex, String> {
+    let mut patterns = REWRITE_PATTERNS.lock().unwrap();
+    if let Some(re) = patterns.get(pattern) {
+        return Ok(re.clone());
+    }
+    let re = compile_rewrite(pattern)?;
+    if patterns.len() >= MAX_REWRITE_PATTERNS {
+        patterns.clear();
+    }
+    patterns.insert(pattern.to_string(), re.clone());
+    Ok(re)
+}
+
+fn compile_rewrite(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|e| format!("invalid rewrite pattern '{}': {}", pattern, e))
+}
+
+/// Strips a prefix on a segment boundary, so `/api` is stripped from
+/// `/api/users` but not from `/apiary`.
+fn strip_prefix<'a>(path: &'a str, prefix: &str) -> &'a str {
+    match path.strip_prefix(prefix.trim_end_matches('/')) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+        _ => path,
+    }
+}
+
+/// Turns the headers of an incoming request into those sent upstream:
+/// hop-by-hop headers are dropped, the Host header follows the proxy's
+/// setting and the configured headers are overlaid.
+pub fn upstream_headers(config: &ProxyConfig, headers: &mut HeaderMap, upgrade: bool) {
+    strip_hop_by_hop(headers, upgrade);
+    // Without a Host header, the HTTP client derives it from the URL
+    if config.host.unwrap_or_default() == HostHeader::Upstream {
+        headers.remove(header::HOST);
+    }
+    for (name, value) in config.headers.iter().flatten() {
+        if let Ok(name) = header::HeaderName::from_bytes(name.as_bytes())
+            && let Ok(value) = header::HeaderValue::from_str(value)
+        {
+            headers.insert(name, value);
+        }
+    }
+}
+
+/// Checks the targets, rewrite patterns and error responses of a proxy.
+pub fn validate(config: &ProxyConfig, strict: bool) -> Result<(), String> {
+    if config.url.is_empty() && config.upstreams.as_ref().is_none_or(|u| u.is_empty()) {
+        return Err("proxy needs a url or upstreams".to_string());
+    }
+    for upstream in config.upstreams.iter().flatten() {
+        if upstream.weight == 0 {
+            return Err(format!("upstream {} has a weight of 0", upstream.url));
+        }
+    }
+    for url in config.urls() {
+        templating::validate_template(url, strict)?;
+    }
+    // Only requests fill the cache, so rejected configs leave nothing behind
+    for rule in config.rewrite.iter().flatten() {
+        compile_rewrite(&rule.pattern)?;
+    }
+    if let Some(ref tls) = config.tls {
+        configure_tls(client_builder(), tls)
+            .and_then(|builder| builder.build().map_err(|e| e.to_string()))
+            .map_err(|e| format!("tls: {}", e))?;
+    }
+    for (kind, response) in config.errors.iter().flatten() {
+        templating::validate_response(response, strict)
+            .map_err(|e| format!("{} error response: {}", failure_name(*kind), e))?;
+    }
+    Ok(())
+}
+
+/// A request on its way upstream or a response on its way back, as seen by
+/// a transform.
+pub struct Message {
+    pub status: Option<StatusCode>,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+/// Applies a transform to a proxied message. Body edits decode the body
+/// according to its `Content-Encoding` and `Content-Type` first, and write
+/// it back uncompressed in the same format.
+pub fn apply_transform(
+    transform: &Transform,
+    message: &mut Message,
+    ctx: &RequestContext,
+) -> Result<(), String> {
+    if let Some(code) = transform.status_code
+        && message.status.is_some()
+    {
+        message.status =
+            Some(StatusCode::from_u16(code).map_err(|_| format!("invalid status {}", code))?);
+    }
+    for name in transform.remove_headers.iter().flatten() {
+        message.headers.remove(name.as_str());
+    }
+    for (name, value) in transform.headers.iter().flatten() {
+        let name = header::HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("invalid header name '{}'", name))?;
+        let value = header::HeaderValue::from_str(value)
+            .map_err(|_| format!("invalid value for header {}", name))?;
+        message.headers.insert(name, value);
+    }
+
+    if !edits_body(transform) {
+        return Ok(());
+    }
+
+    let raw = match message
+        .headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(encoding) => compression::decode(encoding, &message.body)?,
+        None => message.body.to_vec(),
+    };
+    let serializer = message
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|ct| ct.split(';').next())
+        .and_then(|ct| serializers::by_media_type(ct.trim().to_lowercase().as_str()))
+        .filter(|s| s.deserialize.is_some())
+        .unwrap_or(serializers::json());
+    let parsed = (serializer.deserialize.unwrap())(&raw).ok();
+
+    let mut body = match parsed {
+        Some(value) => Body::Structured(value),
+        None => Body::Raw(raw),
+    };
+    if let Some(ref patch) = transform.json_patch {
+        json_patch::patch(body.structured()?, patch).map_err(|e| e.to_string())?;
+    }
+    if let Some(ref patch) = transform.merge_patch {
+        json_patch::merge(body.structured()?, patch);
+    }
+    if let Some(ref script) = transform.script {
+        body = run_script(script, message, body, ctx)?;
+    }
+
+    message.body = match body {
+        Body::Structured(value) => (serializer.serialize)(&value)?.into(),
+        Body::Raw(raw) => raw.into(),
+    };
+    message.headers.remove(header::CONTENT_ENCODING);
+    message.headers.remove(header::CONTENT_LENGTH);
+    Ok(())
+}
+
+/// Whether a transform needs the whole body.
+pub fn edits_body(transform: &Transform) -> bool {
+    transform.json_patch.is_some() || transform.merge_patch.is_some() || transform.script.is_some()
+}
+
+enum Body {
+    Structured(Value),
+    Raw(Vec<u8>),
+}
+
+impl Body {
+    fn structured(&mut self) -> Result<&mut Value, String> {
+        match self {
+            Body::Structured(value) => Ok(value),
+            Body::Raw(_) => Err("body is not structured, cannot patch it".to_string()),
+        }
+    }
+}
+
+/// Runs a Lua transform. The script sees the incoming request as `request`
+/// and the message as `message` with `status`, `headers` and `body`, and
+/// returns the message to use instead.
+fn run_script(
+    script: &str,
+    message: &mut Message,
+    body: Body,
+    ctx: &RequestContext,
+) -> Result<Body, String> {
+    let lua = Lua::new();
+    let globals = lua.globals();
+    globals
+        .set("request", lua.to_value(ctx).map_err(|e| e.to_string())?)
+        .map_err(|e| e.to_string())?;
+
+    let table = lua.create_table().map_err(|e| e.to_string())?;
+    if let Some(status) = message.status {
+        table
+            .set("status", status.as_u16())
+            .map_err(|e| e.to_string())?;
+    }
+    let headers = lua.create_table().map_err(|e| e.to_string())?;
+    for (name, value) in &message.headers {
+        if let Ok(value) = value.to_str() {
+            headers
+                .set(name.as_str(), value)
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    table.set("headers", headers).map_err(|e| e.to_string())?;
+    let body = match body {
+        Body::Structured(value) => lua.to_value(&value).map_err(|e| e.to_string())?,
+        Body::Raw(raw) => LuaValue::String(lua.create_string(&raw).map_err(|e| e.to_string())?),
+    };
+    table.set("body", body).map_err(|e| e.to_string())?;
+    globals.set("message", table).map_err(|e| e.to_string())?;
+
+    let LuaValue::Table(result) = lua.load(script).eval().map_err(|e| e.to_string())? else {
+        return Err("Transform script must return a table".to_string());
+    };
+
+    if message.status.is_some()
+        && let Ok(status) = result.get::<_, u16>("status")
+    {
+        message.status =
+            Some(StatusCode::from_u16(status).map_err(|_| format!("invalid status {}", status))?);
+    }
+    if let Ok(headers) = result.get::<_, Table>("headers") {
+        message.headers.clear();
+        for (name, value) in headers.pairs::<String, String>().flatten() {
+            let name = header::HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("invalid header name '{}'", name))?;
+            let value = header::HeaderValue::from_str(&value)
+                .map_err(|_| format!("invalid value for header {}", name))?;
+            message.headers.append(name, value);
+        }
+    }
+    Ok(
+        match result
+            .get::<_, LuaValue>("body")
+            .map_err(|e| e.to_string())?
+        {
+            LuaValue::String(s) => Body::Raw(s.as_bytes().to_vec()),
+            LuaValue::Nil => Body::Raw(Vec::new()),
+            other => Body::Structured(lua.from_value(other).map_err(|e| e.to_string())?),
+        },
+    )
+}
+
+pub fn client_builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder().user_agent("mimicrab/0.1.0")
+}
+
+/// Client settings that reqwest only supports per client rather than per
+/// request.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+struct ClientSettings {
+    connect_ms: Option<u64>,
+    read_ms: Option<u64>,
+    tls: Option<ProxyTls>,
+    /// Host the TLS server name resolves to
+    server_host: Option<String>,
+}
+
+/// Most clients kept for proxies with their own settings. Templated URLs
+/// with a TLS server name can ask for a client per host.
+const MAX_CLIENTS: usize = 64;
+
+struct CachedClient {
+    client: reqwest::Client,
+    /// TLS files the client was built from and when they were modified
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+    built: Instant,
+}
+
+/// HTTP clients for upstreams. Proxies with their own client settings share
+/// a client per distinct set of settings, everything else uses the default
+/// client. A client is rebuilt once any of its TLS files changes, so
+/// rotated certificates are picked up.
+pub struct Clients {
+    default: reqwest::Client,
+    configured: Mutex<HashMap<ClientSettings, CachedClient>>,
+}
+
+impl Clients {
+    pub fn new(default: reqwest::Client) -> Self {
+        Self {
+            default,
+            configured: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the client for a proxy and the URL to call with it, which
+    /// has the TLS server name as its host if one is set.
+    pub fn get(
+        &self,
+        config: &ProxyConfig,
+        url: &str,
+    ) -> Result<(reqwest::Client, String), String> {
+        let timeouts = config.timeouts.clone().unwrap_or_default();
+        let mut settings = ClientSettings {
+            connect_ms: timeouts.connect_ms,
+            read_ms: timeouts.read_ms,
+            tls: config.tls.clone(),
+            server_host: None,
+        };
+        let mut url = url.to_string();
+        if let Some(server_name) = config.tls.as_ref().and_then(|t| t.server_name.as_ref()) {
+            let mut parsed = reqwest::Url::parse(&url).map_err(|e| e.to_string())?;
+            if parsed.scheme() == "https"
+                && let Some(host) = parsed.host_str()
+            {
+                settings.server_host = Some(
+                    host.trim_start_matches('[')
+                        .trim_end_matches(']')
+                        .to_string(),
+                );
+                parsed
+                    .set_host(Some(server_name))
+                    .map_err(|e| format!("invalid server name '{}': {}", server_name, e))?;
+                url = parsed.to_string();
+            }
+        }
+        if settings == ClientSettings::default() {
+            return Ok((self.default.clone(), url));
+        }
+
+        let files = settings
+            .tls
+            .as_ref()
+            .map(|tls| TlsFiles::resolve(tls).versions())
+            .unwrap_or_default();
+        let mut clients = self.configured.lock().unwrap();
+        if let Some(cached) = clients.get(&settings)
+            && cached.files == files
+        {
+            return Ok((cached.client.clone(), url));
+        }
+        let mut builder = client_builder();
+        if let Some(ms) = settings.connect_ms {
+            builder = builder.connect_timeout(Duration::from_millis(ms));
+        }
+        if let Some(ms) = settings.read_ms {
+            builder = builder.read_timeout(Duration::from_millis(ms));
+        }
+        if let Some(ref tls) = settings.tls {
+            builder = configure_tls(builder, tls)?;
+        }
+        if let (Some(tls), Some(host)) = (&settings.tls, &settings.server_host) {
+            builder = builder.dns_resolver(Arc::new(ServerNameResolver {
+                server_name: tls.server_name.clone().unwrap_or_default(),
+                host: host.clone(),
+            }));
+        }
+        let client = builder.build().map_err(|e| e.to_string())?;
+        if clients.len() >= MAX_CLIENTS
+            && !clients.contains_key(&settings)
+            && let Some(oldest) = clients
+                .iter()
+                .min_by_key(|(_, cached)| cached.built)
+                .map(|(settings, _)| settings.clone())
+        {
+            clients.remove(&oldest);
+        }
+        clients.insert(
+            settings,
+            CachedClient {
+                client: client.clone(),
+                files,
+                built: Instant::now(),
+            },
+        );
+        Ok((client, url))
+    }
+}
+
+/// Files the TLS settings of a proxy point to, with the files of
+/// `secret_dir` standing in for the settings that are not set.
+struct TlsFiles {
+    ca: Vec<PathBuf>,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+}
+
+impl TlsFiles {
+    fn resolve(tls: &ProxyTls) -> Self {
+        let from_secret = |file: &str| {
+            tls.secret_dir
+                .as_ref()
+                .map(|dir| Path::new(dir).join(file))
+                .filter(|path| path.exists())
+        };
+        Self {
+            ca: match tls.ca_files {
+                Some(ref files) => files.iter().map(PathBuf::from).collect(),
+                None => from_secret("ca.crt").into_iter().collect(),
+            },
+            cert: tls
+                .cert_file
+                .as_ref()
+                .map(PathBuf::from)
+                .or_else(|| from_secret("tls.crt")),
+            key: tls
+                .key_file
+                .as_ref()
+                .map(PathBuf::from)
+                .or_else(|| from_secret("tls.key")),
+        }
+    }
+
+    /// Each file with the time it was last modified, following symlinks as
+    /// mounted secrets are swapped through them.
+    fn versions(&self) -> Vec<(PathBuf, Option<SystemTime>)> {
+        self.ca
+            .iter()
+            .chain(&self.cert)
+            .chain(&self.key)
+            .map(|path| {
+                let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+                (path.clone(), modified)
+            })
+            .collect()
+    }
+}
+
+/// Adds the certificates, client identity and verification settings of a
+/// proxy to a client. Files are read every time a client is built.
+pub fn configure_tls(
+    mut builder: reqwest::ClientBuilder,
+    tls: &ProxyTls,
+) -> Result<reqwest::ClientBuilder, String> {
+    let read = |path: &Path| {
+        std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))
+    };
+
+    let TlsFiles { ca, cert, key } = TlsFiles::resolve(tls);
+    for path in ca {
+        let certs = reqwest::Certificate::from_pem_bundle(&read(&path)?)
+            .map_err(|e| format!("invalid CA certificate {}: {}", path.display(), e))?;
+        builder = builder.tls_certs_merge(certs);
+    }
+
+    match (cert, key) {
+        (Some(cert), Some(key)) => {
+            let mut pem = read(&cert)?;
+            pem.push(b'\n');
+            pem.extend(read(&key)?);
+            let identity = reqwest::Identity::from_pem(&pem)
+                .map_err(|e| format!("invalid client certificate {}: {}", cert.display(), e))?;
+            builder = builder.identity(identity);
+        }
+        (None, None) => {}
+        _ => return Err("client certificate and key must be set together".to_string()),
+    }
+
+    Ok(builder.danger_accept_invalid_certs(tls.insecure_skip_verify))
+}
+
+/// Resolves the TLS server name of a proxy to the host its URL pointed at,
+/// so the name only ends up in SNI, certificate checks and the Host header.
+struct ServerNameResolver {
+    server_name: String,
+    host: String,
+}
+
+impl reqwest::dns::Resolve for ServerNameResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = if name.as_str().eq_ignore_ascii_case(&self.server_name) {
+            self.host.clone()
+        } else {
+            name.as_str().to_string()
+        };
+        Box::pin(async move {
+            let addrs: Vec<_> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Picks targets for proxies with several `upstreams` and tracks their
+/// health. Targets are known by their URL template, so proxies sharing a
+/// target share its health.
+#[derive(Default)]
+pub struct Balancer {
+    cursors: Mutex<HashMap<Vec<String>, u64>>,
+    health: Mutex<HashMap<String, Health>>,
+}
+
+#[derive(Default)]
+struct Health {
+    failures: u32,
+    ejected_until: Option<Instant>,
+}
+
+impl Balancer {
+    /// Base URLs to try in order: the picked target, then the other
+    /// healthy ones to fail over to. If every target is ejected, all of
+    /// them are tried rather than none.
+    pub fn targets<'a>(&self, config: &'a ProxyConfig) -> Vec<&'a str> {
+        let upstreams = match config.upstreams {
+            Some(ref upstreams) if !upstreams.is_empty() => upstreams,
+            _ => return vec![config.url.as_str()],
+        };
+        let candidates = self.candidates(upstreams);
+
+        let total_weight: u64 = candidates.iter().map(|u| u64::from(u.weight)).sum();
+        // Mocks loaded from a file or ConfigMap skip validation, so weights
+        // may all be zero; there is nothing to weigh by then
+        let balance = match config.balance.unwrap_or_default() {
+            Balance::Weighted | Balance::Random if total_weight == 0 => Balance::RoundRobin,
+            balance => balance,
+        };
+        let picked = match balance {
+            Balance::RoundRobin => (self.next(&candidates) % candidates.len() as u64) as usize,
+            Balance::Weighted => by_weight(&candidates, self.next(&candidates) % total_weight),
+            Balance::Random => by_weight(
+                &candidates,
+                rng::with_rng(|rng| rng.random_range(0..total_weight)),
+            ),
+        };
+        candidates[picked..]
+            .iter()
+            .chain(&candidates[..picked])
+            .map(|u| u.url.as_str())
+            .collect()
+    }
+
+    /// The first target that is not ejected, for side requests such as
+    /// shadow calls that should not move the rotation of live traffic.
+    pub fn first_target<'a>(&self, config: &'a ProxyConfig) -> &'a str {
+        match config.upstreams {
+            Some(ref upstreams) if !upstreams.is_empty() => &self.candidates(upstreams)[0].url,
+            _ => &config.url,
+        }
+    }
+
+    /// The healthy targets, or all of them if every one is ejected.
+    fn candidates<'a>(&self, upstreams: &'a [Upstream]) -> Vec<&'a Upstream> {
+        let now = Instant::now();
+        let healthy: Vec<&Upstream> = {
+            let health = self.health.lock().unwrap();
+            upstreams
+                .iter()
+                .filter(|u| {
+                    health
+                        .get(&u.url)
+                        .and_then(|h| h.ejected_until)
+                        .is_none_or(|until| until <= now)
+                })
+                .collect()
+        };
+        if healthy.is_empty() {
+            upstreams.iter().collect()
+        } else {
+            healthy
+        }
+    }
+
+    fn next(&self, candidates: &[&Upstream]) -> u64 {
+        let key = candidates.iter().map(|u| u.url.clone()).collect();
+        let mut cursors = self.cursors.lock().unwrap();
+        let cursor = cursors.entry(key).or_default();
+        let current = *cursor;
+        *cursor = cursor.wrapping_add(1);
+        current
+    }
+
+    /// Records the outcome of a call to a target, ejecting it after too
+    /// many failures in a row.
+    pub fn report(&self, config: &ProxyConfig, url: &str, failed: bool) {
+        if config.upstreams.as_ref().is_none_or(|u| u.is_empty()) {
+            return;
+        }
+        let mut health = self.health.lock().unwrap();
+        let target = health.entry(url.to_string()).or_default();
+        if !failed {
+            target.failures = 0;
+            return;
+        }
+        target.failures += 1;
+        let ejection = config.ejection.clone().unwrap_or_default();
+        if target.failures >= ejection.consecutive_failures {
+            tracing::warn!(
+                "Ejecting upstream {} for {}ms after {} failures",
+                url,
+                ejection.eject_ms,
+                target.failures
+            );
+            metrics::UPSTREAM_EJECTIONS.with_label_values(&[url]).inc();
+            target.failures = 0;
+            target.ejected_until = Some(Instant::now() + Duration::from_millis(ejection.eject_ms));
+        }
+    }
+}
+
+/// Index of the target a point in `0..total_weight` falls on.
+fn by_weight(candidates: &[&Upstream], mut point: u64) -> usize {
+    for (i, upstream) in candidates.iter().enumerate() {
+        if point < u64::from(upstream.weight) {
+            return i;
+        }
+        point -= u64::from(upstream.weight);
+    }
+    candidates.len() - 1
+}
+
+/// Whether a failure happened before the upstream saw the request, so
+/// another target can be tried.
+pub fn fails_over(kind: UpstreamFailure) -> bool {
+    matches!(
+        kind,
+        UpstreamFailure::Dns | UpstreamFailure::Refused | UpstreamFailure::Connect
+    )
+}
+
+/// A call to an upstream that did not produce a response.
+pub struct Failure {
+    pub kind: UpstreamFailure,
+    pub error: String,
+}
+
+impl From<reqwest::Error> for Failure {
+    fn from(error: reqwest::Error) -> Self {
+        Self {
+            kind: classify(&error),
+            error: error.to_string(),
+        }
+    }
+}
+
+pub fn failure_name(kind: UpstreamFailure) -> &'static str {
+    match kind {
+        UpstreamFailure::Timeout => "timeout",
+        UpstreamFailure::Dns => "dns",
+        UpstreamFailure::Refused => "refused",
+        UpstreamFailure::Connect => "connect",
+        UpstreamFailure::Other => "other",
+    }
+}
+
+/// Works out why a call failed from the chain of errors behind it.
+fn classify(error: &reqwest::Error) -> UpstreamFailure {
+    if error.is_timeout() {
+        return UpstreamFailure::Timeout;
+    }
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        if let Some(io) = cause.downcast_ref::<std::io::Error>() {
+            match io.kind() {
+                std::io::ErrorKind::ConnectionRefused => return UpstreamFailure::Refused,
+                std::io::ErrorKind::TimedOut => return UpstreamFailure::Timeout,
+                _ => {}
+            }
+        }
+        if cause.to_string().starts_with("dns error") {
+            return UpstreamFailure::Dns;
+        }
+        source = cause.source();
+    }
+    if error.is_connect() {
+        UpstreamFailure::Connect
+    } else {
+        UpstreamFailure::Other
+    }
+}
+
+/// Body of an upstream response, read up front or passed on as it arrives.
+pub enum ResponseBody {
+    Buffered(Bytes),
+    Streamed(reqwest::Response),
+}
+
+pub struct UpstreamResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: ResponseBody,
+}
+
+/// Calls the upstream. With `buffer` the response body is read as part of
+/// the call, otherwise it is left to be streamed. A `stream` is sent in
+/// place of the message body; it can only be sent once, so it is never
+/// retried.
+///
+/// Idempotent requests are retried on failures, 429 and 5xx responses if
+/// the proxy has a retry policy, doubling the backoff after every attempt.
+pub async fn exchange(
+    client: &reqwest::Client,
+    config: &ProxyConfig,
+    method: &Method,
+    url: &str,
+    request: &Message,
+    stream: Option<reqwest::Body>,
+    buffer: bool,
+) -> Result<UpstreamResponse, Failure> {
+    let (max_retries, mut backoff_ms) = match config.retry {
+        Some(ref retry) if method.is_idempotent() && stream.is_none() => {
+            (retry.max_retries, retry.backoff_ms)
+        }
+        _ => (0, 0),
+    };
+    let mut stream = stream;
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let outcome = attempt(client, config, method, url, request, stream.take(), buffer).await;
+        let retry_reason = match outcome {
+            Ok(ref response) => (response.status == StatusCode::TOO_MANY_REQUESTS
+                || response.status.is_server_error())
+            .then_some("status"),
+            Err(ref failure) => Some(failure_name(failure.kind)),
+        };
+        let Some(reason) = retry_reason.filter(|_| attempts <= max_retries) else {
+            return outcome;
+        };
+        tracing::warn!(
+            "Upstream {} attempt {} failed ({}), retrying in {}ms",
+            url,
+            attempts,
+            reason,
+            backoff_ms
+        );
+        metrics::UPSTREAM_RETRIES.with_label_values(&[reason]).inc();
+        tokio::time::sleep(Duration::from_millis(backoff_ms)).await;
+        backoff_ms = backoff_ms.saturating_mul(2);
+    }
+}
+
+async fn attempt(
+    client: &reqwest::Client,
+    config: &ProxyConfig,
+    method: &Method,
+    url: &str,
+    request: &Message,
+    stream: Option<reqwest::Body>,
+    buffer: bool,
+) -> Result<UpstreamResponse, Failure> {
+    let mut builder = client
+        .request(method.clone(), url)
+        .headers(request.headers.clone());
+    if let Some(stream) = stream {
+        builder = builder.body(stream);
+    } else if !request.body.is_empty() {
+        builder = builder.body(request.body.clone());
+    }
+    if let Some(ms) = config.timeouts.as_ref().and_then(|t| t.total_ms) {
+        builder = builder.timeout(Duration::from_millis(ms));
+    }
+
+    let response = builder.send().await?;
+    let status = response.status();
+    let mut headers = response.headers().clone();
+    strip_hop_by_hop(&mut headers, status == StatusCode::SWITCHING_PROTOCOLS);
+    let body = if buffer {
+        ResponseBody::Buffered(response.bytes().await?)
+    } else {
+        ResponseBody::Streamed(response)
+    };
+    Ok(UpstreamResponse {
+        status,
+        headers,
+        body,
+    })
+}
+
+/// Headers that only apply to a single connection and must not be
+/// forwarded by a proxy (RFC 9110, section 7.6.1).
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Removes hop-by-hop headers, including those named in `Connection`. An
+/// upgrade keeps `Connection: upgrade` and its `Upgrade` header.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap, upgrade: bool) {
+    let listed: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    let protocol = headers.get(header::UPGRADE).cloned();
+
+    for name in listed.iter().map(String::as_str).chain(HOP_BY_HOP_HEADERS) {
+        headers.remove(name);
+    }
+
+    if upgrade && let Some(protocol) = protocol {
+        headers.insert(
+            header::CONNECTION,
+            header::HeaderValue::from_static("upgrade"),
+        );
+        headers.insert(header::UPGRADE, protocol);
+    }
+}
+
+/// Whether a request asks to switch protocols, e.g. to a WebSocket.
+pub fn is_upgrade(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::UPGRADE)
+        && headers
+            .get_all(header::CONNECTION)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+/// Whether a request to this proxy can be sent upstream while its body is
+/// still arriving: nothing may need to read the body first, or send it
+/// twice for a retry or failover. Scripts of both transforms see the
+/// request, so either one needs its body.
+pub fn streams_request(config: &ProxyConfig, record: Option<&RecordConfig>) -> bool {
+    config.retry.is_none()
+        && config.urls().len() == 1
+        && !config
+            .transform
+            .as_ref()
+            .and_then(|t| t.request.as_ref())
+            .is_some_and(edits_body)
+        && config
+            .transform
+            .as_ref()
+            .and_then(|t| t.response.as_ref())
+            .is_none_or(|t| t.script.is_none())
+        && config.record.as_ref().or(record).is_none()
+        && !config.urls().into_iter().any(templating::references_body)
+        && !config
+            .errors
+            .iter()
+            .flat_map(|errors| errors.values())
+            .any(|res| {
+                serde_json::to_string(res).is_ok_and(|res| templating::references_body(&res))
+            })
+}
+
+/// Whether the response of this proxy has to be read in full before it is
+/// returned, because it is transformed or recorded.
+pub fn buffers_response(config: &ProxyConfig, record: Option<&RecordConfig>) -> bool {
+    config
+        .transform
+        .as_ref()
+        .and_then(|t| t.response.as_ref())
+        .is_some_and(edits_body)
+        || config.record.as_ref().or(record).is_some()
+}
+
+/// Joins an upgraded client connection with the upgraded upstream one and
+/// copies bytes both ways until either side closes.
+pub fn tunnel(client: hyper::upgrade::OnUpgrade, upstream: reqwest::Response) {
+    tokio::spawn(async move {
+        let connections =
+            tokio::try_join!(async { client.await.map_err(|e| e.to_string()) }, async {
+                upstream.upgrade().await.map_err(|e| e.to_string())
+            });
+        let (client, mut upstream) = match connections {
+            Ok(connections) => connections,
+            Err(e) => {
+                tracing::error!("Upgrade failed: {}", e);
+                return;
+            }
+        };
+        let mut client = hyper_util::rt::TokioIo::new(client);
+        if let Err(e) = tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+            tracing::debug!("Upgraded connection closed: {}", e);
+        }
+    });
+}
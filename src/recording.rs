@@ -0,0 +1,161 @@
+use crate::context::RequestContext;
+use crate::models::{
+    Expectation, MockResponse, RecordConfig, RequestCondition, ResponseConfig, ResponseStatus,
+};
+use axum::http::{HeaderMap, StatusCode, header};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Response headers that describe the upstream connection rather than the
+/// response, and are therefore not recorded.
+const SKIPPED_HEADERS: [header::HeaderName; 6] = [
+    header::CONNECTION,
+    header::CONTENT_LENGTH,
+    header::CONTENT_ENCODING,
+    header::TRANSFER_ENCODING,
+    header::DATE,
+    header::VARY,
+];
+
+/// Parses `--record-match` values such as `query`, `body` or
+/// `header:x-tenant`.
+pub fn parse_match_keys(keys: &[String]) -> Result<RecordConfig, String> {
+    let mut config = RecordConfig::default();
+    for key in keys {
+        match key.as_str() {
+            "method" | "path" => {}
+            "query" => config.query = true,
+            "body" => config.body = true,
+            other => match other.strip_prefix("header:") {
+                Some(name) if !name.is_empty() => config.headers.push(name.to_lowercase()),
+                _ => return Err(format!("unknown record match key '{}'", other)),
+            },
+        }
+    }
+    Ok(config)
+}
+
+/// Turns a proxied exchange into an expectation. Returns `None` if the
+/// upstream body cannot be stored, e.g. because it is binary.
+pub fn to_expectation(
+    config: &RecordConfig,
+    ctx: &RequestContext,
+    status: StatusCode,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Option<Expectation> {
+    let condition = RequestCondition {
+        method: Some(ctx.method.clone()),
+        path: Some(ctx.path.clone()),
+        query: (config.query && !ctx.query.is_empty()).then(|| ctx.query.clone()),
+        headers: {
+            let selected: HashMap<String, String> = config
+                .headers
+                .iter()
+                .filter_map(|name| {
+                    let name = name.to_lowercase();
+                    ctx.headers.get(&name).map(|v| (name, v.clone()))
+                })
+                .collect();
+            (!selected.is_empty()).then_some(selected)
+        },
+        body: if config.body { ctx.body.clone() } else { None },
+    };
+
+    // reqwest hands over the body as sent, so undo any compression first
+    let body = match headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(encoding) => crate::compression::decode(encoding, body).ok()?,
+        None => body.to_vec(),
+    };
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    let (body, body_type) = if body.is_empty() {
+        (None, None)
+    } else {
+        match recorded_body(content_type, &body) {
+            Some(recorded) => recorded,
+            None => {
+                tracing::warn!(
+                    "Not recording {} {}: body is not text",
+                    ctx.method,
+                    ctx.path
+                );
+                return None;
+            }
+        }
+    };
+
+    let mut response_headers = HashMap::new();
+    for (name, value) in headers {
+        if SKIPPED_HEADERS.contains(name) {
+            continue;
+        }
+        if let Ok(value) = value.to_str() {
+            response_headers
+                .entry(name.as_str().to_string())
+                .and_modify(|existing: &mut String| {
+                    existing.push_str(", ");
+                    existing.push_str(value);
+                })
+                .or_insert_with(|| value.to_string());
+        }
+    }
+
+    Some(Expectation {
+        id: 0,
+        condition,
+        response: MockResponse {
+            response: ResponseConfig {
+                status_code: Some(ResponseStatus::Code(status.as_u16())),
+                headers: (!response_headers.is_empty()).then_some(response_headers),
+                body,
+                body_type,
+                latency: None,
+                fault: None,
+                negotiate: Some(false),
+                compression: None,
+                declared_encoding: None,
+            },
+            jitter: None,
+            proxy: None,
+            script: None,
+            strict_templates: None,
+            callbacks: None,
+            shadow: None,
+        },
+    })
+}
+
+/// Structured bodies are stored as JSON in the format they came in, other
+/// text as a text body.
+fn recorded_body(
+    content_type: Option<&str>,
+    body: &[u8],
+) -> Option<(Option<Value>, Option<String>)> {
+    let media_type = content_type
+        .and_then(|ct| ct.split(';').next())
+        .map(|ct| ct.trim().to_lowercase());
+    if let Some(serializer) = media_type
+        .as_deref()
+        .and_then(crate::serializers::by_media_type)
+        && let Some(deserialize) = serializer.deserialize
+        && let Ok(value) = deserialize(body)
+    {
+        return Some((Some(value), Some(serializer.name.to_string())));
+    }
+    let text = String::from_utf8(body.to_vec()).ok()?;
+    Some((Some(Value::String(text)), Some("text".to_string())))
+}
+
+/// Whether an expectation with the same condition is already configured.
+pub fn is_duplicate(expectations: &[Expectation], recorded: &Expectation) -> bool {
+    let condition = serde_json::to_value(&recorded.condition).ok();
+    expectations
+        .iter()
+        .any(|exp| serde_json::to_value(&exp.condition).ok() == condition)
+}
@@ -0,0 +1,23 @@
+use once_cell::sync::Lazy;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+// Shared source of randomness for jitter and latency so a fixed seed makes
+// a sequence of requests reproducible.
+static RNG: Lazy<Mutex<StdRng>> = Lazy::new(|| Mutex::new(StdRng::from_os_rng()));
+
+pub fn seed(seed: u64) {
+    *lock() = StdRng::seed_from_u64(seed);
+    tracing::info!("Random number generator seeded with {}", seed);
+}
+
+pub fn with_rng<T>(f: impl FnOnce(&mut StdRng) -> T) -> T {
+    f(&mut lock())
+}
+
+// A panic while drawing leaves the generator in a usable state, so a
+// poisoned lock is recovered rather than failing every later draw.
+fn lock() -> MutexGuard<'static, StdRng> {
+    RNG.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,326 @@
+use serde_json::Value;
+
+type Deserialize = fn(&[u8]) -> Result<Value, String>;
+
+/// A body format that can be selected by `body_type` or negotiated through
+/// the `Accept` header, and decoded from request bodies by `Content-Type`.
+pub struct Serializer {
+    /// Name used in `body_type`
+    pub name: &'static str,
+    /// Content-Type of the serialized body
+    pub content_type: &'static str,
+    /// Media types in `Accept` that select this format
+    pub media_types: &'static [&'static str],
+    pub serialize: fn(&Value) -> Result<Vec<u8>, String>,
+    /// Reads request bodies, if the format can be decoded
+    pub deserialize: Option<Deserialize>,
+}
+
+/// Every supported format. JSON comes first and is the default; register a
+/// new format by adding it here.
+static SERIALIZERS: [Serializer; 6] = [
+    Serializer {
+        name: "json",
+        content_type: "application/json",
+        media_types: &["application/json"],
+        serialize: |val| serde_json::to_vec(val).map_err(|e| e.to_string()),
+        deserialize: Some(|bytes| serde_json::from_slice(bytes).map_err(|e| e.to_string())),
+    },
+    Serializer {
+        name: "xml",
+        content_type: "application/xml",
+        media_types: &["application/xml", "text/xml"],
+        serialize: |val| Ok(to_xml(val).into_bytes()),
+        deserialize: None,
+    },
+    Serializer {
+        name: "yaml",
+        content_type: "application/yaml",
+        media_types: &["application/yaml", "application/x-yaml", "text/yaml"],
+        serialize: |val| {
+            serde_yaml::to_string(val)
+                .map(String::into_bytes)
+                .map_err(|e| e.to_string())
+        },
+        deserialize: Some(|bytes| serde_yaml::from_slice(bytes).map_err(|e| e.to_string())),
+    },
+    Serializer {
+        name: "msgpack",
+        content_type: "application/msgpack",
+        media_types: &[
+            "application/msgpack",
+            "application/x-msgpack",
+            "application/vnd.msgpack",
+        ],
+        serialize: |val| rmp_serde::to_vec_named(val).map_err(|e| e.to_string()),
+        deserialize: Some(|bytes| rmp_serde::from_slice(bytes).map_err(|e| e.to_string())),
+    },
+    Serializer {
+        name: "cbor",
+        content_type: "application/cbor",
+        media_types: &["application/cbor"],
+        serialize: |val| {
+            let mut bytes = Vec::new();
+            ciborium::into_writer(val, &mut bytes).map_err(|e| e.to_string())?;
+            Ok(bytes)
+        },
+        deserialize: Some(|bytes| ciborium::from_reader(bytes).map_err(|e| e.to_string())),
+    },
+    Serializer {
+        name: "bson",
+        content_type: "application/bson",
+        media_types: &["application/bson"],
+        serialize: to_bson,
+        deserialize: Some(|bytes| bson::from_slice(bytes).map_err(|e| e.to_string())),
+    },
+];
+
+pub fn json() -> &'static Serializer {
+    &SERIALIZERS[0]
+}
+
+pub fn by_name(name: &str) -> Option<&'static Serializer> {
+    SERIALIZERS.iter().find(|s| s.name == name)
+}
+
+pub fn by_media_type(media_type: &str) -> Option<&'static Serializer> {
+    SERIALIZERS
+        .iter()
+        .find(|s| s.media_types.contains(&media_type))
+}
+
+/// Decodes a request body according to its `Content-Type`. Bodies without
+/// a known structured type are tried as JSON, like they always were.
+pub fn decode_body(content_type: Option<&str>, bytes: &[u8]) -> Option<Value> {
+    if bytes.is_empty() {
+        return None;
+    }
+    let media_type = content_type
+        .and_then(|ct| ct.split(';').next())
+        .map(|ct| ct.trim().to_lowercase());
+    let deserialize = media_type
+        .as_deref()
+        .and_then(by_media_type)
+        .and_then(|s| s.deserialize)
+        .unwrap_or(json().deserialize.unwrap());
+    deserialize(bytes)
+        .inspect_err(|e| tracing::trace!("Request body is not structured: {}", e))
+        .ok()
+}
+
+/// Picks the format to answer in according to `Accept` and its q-values.
+/// The format with the highest q-value wins, more specific media ranges
+/// take precedence over wildcards and ties go to the earlier range in the
+/// header. The mock's own format is kept if it ties for the highest
+/// q-value, or if the client only accepts it through a wildcard, so a
+/// browser's `*/*` does not trade it for a format the browser names.
+/// Returns `None` if the client accepts none of the formats.
+pub fn negotiate(accept: &str, preferred: &'static Serializer) -> Option<&'static Serializer> {
+    let ranges = parse_accept(accept);
+    let own = quality(&ranges, preferred).filter(|(_, q, _)| *q > 0.0);
+
+    let mut best: Option<(&Serializer, (f32, u8, usize))> = None;
+    for serializer in SERIALIZERS.iter() {
+        let Some((index, q, specificity)) = quality(&ranges, serializer) else {
+            continue;
+        };
+        if q <= 0.0 {
+            continue;
+        }
+        let better = match best {
+            None => true,
+            Some((_, (best_q, best_spec, best_index))) => {
+                q > best_q
+                    || (q == best_q && specificity > best_spec)
+                    || (q == best_q && specificity == best_spec && index < best_index)
+            }
+        };
+        if better {
+            best = Some((serializer, (q, specificity, index)));
+        }
+    }
+    match (own, best) {
+        (Some((_, q, specificity)), Some((_, (best_q, _, _))))
+            if specificity < 2 || q >= best_q =>
+        {
+            Some(preferred)
+        }
+        (_, best) => best.map(|(serializer, _)| serializer),
+    }
+}
+
+/// The most specific range matching a format, which decides its q-value:
+/// its index in the header, the q-value and how specific it is.
+fn quality(ranges: &[(String, f32)], serializer: &Serializer) -> Option<(usize, f32, u8)> {
+    ranges
+        .iter()
+        .enumerate()
+        .filter_map(|(i, (range, q))| {
+            serializer
+                .media_types
+                .iter()
+                .filter_map(|media_type| specificity(range, media_type))
+                .max()
+                .map(|spec| (i, *q, spec))
+        })
+        .max_by_key(|(_, _, spec)| *spec)
+}
+
+fn parse_accept(accept: &str) -> Vec<(String, f32)> {
+    accept
+        .split(',')
+        .filter_map(|part| {
+            let mut params = part.split(';');
+            let range = params.next()?.trim().to_lowercase();
+            if range.is_empty() {
+                return None;
+            }
+            let q = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((range, q.clamp(0.0, 1.0)))
+        })
+        .collect()
+}
+
+/// How specifically a media range matches a media type: 2 for an exact
+/// match, 1 for `type/*`, 0 for `*/*`.
+fn specificity(range: &str, media_type: &str) -> Option<u8> {
+    if range == media_type {
+        return Some(2);
+    }
+    if range == "*/*" {
+        return Some(0);
+    }
+    let prefix = range.strip_suffix("/*")?;
+    media_type
+        .split_once('/')
+        .filter(|(kind, _)| *kind == prefix)
+        .map(|_| 1)
+}
+
+/// BSON needs a document at the top level, so arrays are wrapped in
+/// `{"data": [...]}`.
+fn to_bson(val: &Value) -> Result<Vec<u8>, String> {
+    let doc = match bson::to_bson(val).map_err(|e| e.to_string())? {
+        bson::Bson::Document(doc) => doc,
+        bson::Bson::Array(arr) => bson::doc! { "data": arr },
+        _ => return Err("BSON bodies must be objects or arrays".to_string()),
+    };
+    let mut bytes = Vec::new();
+    doc.to_writer(&mut bytes).map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+/// Converts JSON to XML:
+/// - an object with a single key uses that key as the root element,
+///   anything else is wrapped in `<root>`
+/// - object keys become child elements, except `@name` keys which become
+///   attributes and `#text` which becomes the element's text
+/// - arrays repeat the element of their key, or use `<item>` when they have
+///   no key of their own
+/// - `null` becomes an empty element
+/// - characters that are not allowed in element names are replaced with `_`
+fn to_xml(val: &Value) -> String {
+    let mut out = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    match val {
+        Value::Object(obj) if obj.len() == 1 && !is_special_key(obj.keys().next().unwrap()) => {
+            let (key, val) = obj.iter().next().unwrap();
+            if val.is_array() {
+                write_element(&mut out, "root", &Value::Object(obj.clone()));
+            } else {
+                write_element(&mut out, key, val);
+            }
+        }
+        _ => write_element(&mut out, "root", val),
+    }
+    out
+}
+
+fn is_special_key(key: &str) -> bool {
+    key.starts_with('@') || key == "#text"
+}
+
+fn write_element(out: &mut String, name: &str, val: &Value) {
+    let name = xml_name(name);
+    match val {
+        Value::Array(items) => {
+            out.push_str(&format!("<{}>", name));
+            for item in items {
+                write_element(out, "item", item);
+            }
+            out.push_str(&format!("</{}>", name));
+        }
+        Value::Object(obj) => {
+            out.push_str(&format!("<{}", name));
+            for (key, attr) in obj.iter().filter(|(k, _)| k.starts_with('@')) {
+                out.push_str(&format!(
+                    " {}=\"{}\"",
+                    xml_name(&key[1..]),
+                    xml_escape(&scalar_text(attr))
+                ));
+            }
+            out.push('>');
+            if let Some(text) = obj.get("#text") {
+                out.push_str(&xml_escape(&scalar_text(text)));
+            }
+            for (key, child) in obj.iter().filter(|(k, _)| !is_special_key(k)) {
+                match child {
+                    // Arrays inside objects repeat the child element
+                    Value::Array(items) => {
+                        for item in items {
+                            write_element(out, key, item);
+                        }
+                    }
+                    _ => write_element(out, key, child),
+                }
+            }
+            out.push_str(&format!("</{}>", name));
+        }
+        Value::Null => out.push_str(&format!("<{}/>", name)),
+        _ => out.push_str(&format!(
+            "<{}>{}</{}>",
+            name,
+            xml_escape(&scalar_text(val)),
+            name
+        )),
+    }
+}
+
+fn scalar_text(val: &Value) -> String {
+    match val {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+fn xml_name(name: &str) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '_' | '-' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if !sanitized
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_alphabetic() || c == '_')
+    {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
@@ -0,0 +1,260 @@
+use crate::models::ShadowConfig;
+use crate::proxy::Message;
+use crate::{compression, metrics, serializers};
+use axum::http::{Method, header};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::Mutex;
+
+/// A way in which the upstream response differed from the mock's.
+#[derive(Clone, Debug, Serialize)]
+pub struct Difference {
+    /// `status`, `header` or `body`
+    pub kind: &'static str,
+    /// Header name, or JSON pointer into the body
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    pub mock: Value,
+    pub upstream: Value,
+}
+
+/// Outcome of a shadowed request whose upstream response did not match the
+/// mock, or that could not be sent.
+#[derive(Clone, Debug, Serialize)]
+pub struct DriftReport {
+    pub timestamp: String,
+    pub expectation_id: u64,
+    pub method: String,
+    pub path: String,
+    pub differences: Vec<Difference>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The latest drift reports, oldest first.
+pub struct Drift {
+    capacity: usize,
+    reports: Mutex<VecDeque<DriftReport>>,
+}
+
+impl Drift {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            reports: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn reports(&self, expectation_id: Option<u64>) -> Vec<DriftReport> {
+        self.reports
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| expectation_id.is_none_or(|id| r.expectation_id == id))
+            .cloned()
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.reports.lock().unwrap().clear();
+    }
+
+    /// Compares the upstream's answer to a shadowed request with the mock
+    /// response, keeping a report if they differ.
+    pub fn record(
+        &self,
+        expectation_id: u64,
+        method: &Method,
+        path: &str,
+        config: &ShadowConfig,
+        mock: &Message,
+        upstream: Result<Message, String>,
+    ) {
+        let (differences, error) = match upstream {
+            Ok(ref upstream) => (compare(config, mock, upstream), None),
+            Err(e) => (vec![], Some(e)),
+        };
+        let result = match (&error, differences.is_empty()) {
+            (Some(_), _) => "error",
+            (None, true) => "match",
+            (None, false) => "drift",
+        };
+        metrics::SHADOW_COMPARISONS
+            .with_label_values(&[expectation_id.to_string().as_str(), result])
+            .inc();
+        if result == "match" {
+            return;
+        }
+        tracing::warn!(
+            "Mock {} drifted from its upstream for {} {}: {} differences{}",
+            expectation_id,
+            method,
+            path,
+            differences.len(),
+            error
+                .as_ref()
+                .map(|e| format!(", {}", e))
+                .unwrap_or_default()
+        );
+
+        let mut reports = self.reports.lock().unwrap();
+        if reports.len() >= self.capacity {
+            reports.pop_front();
+        }
+        reports.push_back(DriftReport {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            expectation_id,
+            method: method.to_string(),
+            path: path.to_string(),
+            differences,
+            error,
+        });
+    }
+}
+
+/// Checks that the ignored paths are JSON pointers into the body. The root
+/// pointer `""` would ignore the whole body.
+pub fn validate(config: &ShadowConfig) -> Result<(), String> {
+    match config
+        .ignore
+        .iter()
+        .find(|pointer| !pointer.starts_with('/'))
+    {
+        Some(pointer) => Err(format!("ignore pointer '{}' must start with '/'", pointer)),
+        None => Ok(()),
+    }
+}
+
+/// Compares status, the configured headers and the structure of the body:
+/// object keys and the types of values, with arrays compared by their first
+/// element. Values themselves are not compared.
+pub fn compare(config: &ShadowConfig, mock: &Message, upstream: &Message) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    if mock.status != upstream.status {
+        differences.push(Difference {
+            kind: "status",
+            path: None,
+            mock: json!(mock.status.map(|s| s.as_u16())),
+            upstream: json!(upstream.status.map(|s| s.as_u16())),
+        });
+    }
+
+    for name in &config.headers {
+        let value = |message: &Message| {
+            message
+                .headers
+                .get(name.as_str())
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let (from_mock, from_upstream) = (value(mock), value(upstream));
+        if from_mock != from_upstream {
+            differences.push(Difference {
+                kind: "header",
+                path: Some(name.to_lowercase()),
+                mock: json!(from_mock),
+                upstream: json!(from_upstream),
+            });
+        }
+    }
+
+    match (structured_body(mock), structured_body(upstream)) {
+        (Some(from_mock), Some(from_upstream)) => {
+            compare_structure("", &from_mock, &from_upstream, config, &mut differences)
+        }
+        (None, None) => {}
+        (from_mock, from_upstream) => differences.push(Difference {
+            kind: "body",
+            path: Some(String::new()),
+            mock: json!(from_mock.as_ref().map_or("unstructured", type_name)),
+            upstream: json!(from_upstream.as_ref().map_or("unstructured", type_name)),
+        }),
+    }
+    differences
+}
+
+fn structured_body(message: &Message) -> Option<Value> {
+    if message.body.is_empty() {
+        return None;
+    }
+    let raw = match message
+        .headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(encoding) => compression::decode(encoding, &message.body).ok()?,
+        None => message.body.to_vec(),
+    };
+    let content_type = message
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    serializers::decode_body(content_type, &raw)
+}
+
+fn compare_structure(
+    path: &str,
+    mock: &Value,
+    upstream: &Value,
+    config: &ShadowConfig,
+    differences: &mut Vec<Difference>,
+) {
+    if is_ignored(path, &config.ignore) {
+        return;
+    }
+    match (mock, upstream) {
+        (Value::Object(from_mock), Value::Object(from_upstream)) => {
+            let keys: BTreeSet<&String> = from_mock.keys().chain(from_upstream.keys()).collect();
+            for key in keys {
+                let child = format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"));
+                match (from_mock.get(key), from_upstream.get(key)) {
+                    (Some(a), Some(b)) => compare_structure(&child, a, b, config, differences),
+                    (a, b) if !is_ignored(&child, &config.ignore) => differences.push(Difference {
+                        kind: "body",
+                        path: Some(child),
+                        mock: json!(a.map_or("missing", type_name)),
+                        upstream: json!(b.map_or("missing", type_name)),
+                    }),
+                    _ => {}
+                }
+            }
+        }
+        (Value::Array(from_mock), Value::Array(from_upstream)) => {
+            if let (Some(a), Some(b)) = (from_mock.first(), from_upstream.first()) {
+                compare_structure(&format!("{}/0", path), a, b, config, differences);
+            }
+        }
+        _ if type_name(mock) != type_name(upstream) => differences.push(Difference {
+            kind: "body",
+            path: Some(path.to_string()),
+            mock: json!(type_name(mock)),
+            upstream: json!(type_name(upstream)),
+        }),
+        _ => {}
+    }
+}
+
+/// Whether a JSON pointer is, or is inside, one of the ignored ones.
+fn is_ignored(path: &str, ignore: &[String]) -> bool {
+    let segments: Vec<&str> = path.split('/').skip(1).collect();
+    ignore.iter().any(|pattern| {
+        let pattern: Vec<&str> = pattern.split('/').skip(1).collect();
+        pattern.len() <= segments.len()
+            && pattern
+                .iter()
+                .zip(&segments)
+                .all(|(p, s)| *p == "*" || p == s)
+    })
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
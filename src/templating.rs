@@ -1,104 +1,460 @@
+use crate::context::RequestContext;
+use crate::models::{MockResponse, ResponseConfig, ResponseStatus};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::format::{Item, StrftimeItems};
+use fake::Fake;
+use fake::faker::{address::en as address, company::en as company, internet::en as internet};
+use fake::faker::{lorem::en as lorem, name::en as name};
+use handlebars::{
+    Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderErrorReason,
+};
 use once_cell::sync::Lazy;
+use rand::Rng;
+use rand::distr::Alphanumeric;
 use regex::Regex;
 use serde_json::Value;
+use serde_json_path::JsonPath;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+// Filter chains such as `:lower:trim` or `:date:"%H:%M"`
+const FILTERS: &str = r#"((?::(?:"[^"]*"|'[^']*'|[^:}"'\s]+))*)"#;
 
 static PATH_RE: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r"\{\{path\[(\d+)\](?::([a-z]+))?\}\}").unwrap());
-static BODY_RE: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r"\{\{body([\.\[][a-zA-Z0-9\._\[\]]+)(?::([a-z]+))?\}\}").unwrap());
-
-pub fn resolve_template(template: &str, path_segments: &[&str], body: &Option<Value>) -> String {
-    let mut resolved = template.to_string();
-
-    // Resolve path segments: {{path[0]}}, {{path[1]}}, etc.
-    resolved = PATH_RE
-        .replace_all(&resolved, |caps: &regex::Captures| {
-            let index: usize = caps[1].parse().unwrap_or(999);
-            let val = path_segments.get(index).cloned().unwrap_or("null");
-            tracing::trace!("Template resolved path[{}]: {}", index, val);
-            val.to_string()
-        })
-        .to_string();
-
-    // Resolve body values: {{body.some.field}} or {{body[0].name}}
-    resolved = BODY_RE
-        .replace_all(&resolved, |caps: &regex::Captures| {
-            let path = &caps[1];
-            let val = if let Some(body_val) = body {
-                get_value_by_path(body_val, path)
-                    .map(|v| {
-                        if v.is_string() {
-                            v.as_str().unwrap().to_string()
-                        } else {
-                            v.to_string().replace("\"", "")
-                        }
-                    })
-                    .unwrap_or_else(|| "null".to_string())
-            } else {
-                "null".to_string()
-            };
-            tracing::trace!("Template resolved body{}: {}", path, val);
-            val
-        })
-        .to_string();
+    Lazy::new(|| Regex::new(&format!(r"\{{\{{path\[(\d+)\]{FILTERS}\}}\}}")).unwrap());
+static BODY_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(&format!(
+        r"\{{\{{body([\.\[][a-zA-Z0-9\._\[\]]+){FILTERS}\}}\}}"
+    ))
+    .unwrap()
+});
+// `{{$.items[?(@.sku=='A-1')].price}}` or `{{body['content-type']}}`: dot
+// members, `..` and bracket segments, which may hold quoted keys and one
+// level of nested brackets
+static JSONPATH_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(&format!(
+        r#"\{{\{{(\$|body)((?:\.\.?(?:\*|[^\s\.\[\]:{{}}'"]+)|\.\.|\[(?:'[^']*'|"[^"]*"|\[[^\]]*\]|[^\]\['"])*\])*){FILTERS}\}}\}}"#
+    ))
+    .unwrap()
+});
+static MAP_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(&format!(
+        r"\{{\{{(headers|query|cookies)\.([a-zA-Z0-9\-_\.]+){FILTERS}\}}\}}"
+    ))
+    .unwrap()
+});
+static REQUEST_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(&format!(r"\{{\{{(method|path|rawBody){FILTERS}\}}\}}")).unwrap());
+static HELPER_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(&format!(
+        r#"\{{\{{(now|uuid|randomInt|randomString|counter|faker\.[a-zA-Z]+)((?:\s+(?:"[^"]*"|'[^']*'|[^\s}}:"']+))*)\s*{FILTERS}\}}\}}"#
+    ))
+    .unwrap()
+});
+static FILTER_TOKEN_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#":(?:"([^"]*)"|'([^']*)'|([^:}"'\s]+))"#).unwrap());
+static HELPER_ARG_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#""([^"]*)"|'([^']*)'|([^\s]+)"#).unwrap());
+// Anything that looks like a marker, known or not
+static ANY_MARKER_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\{\{.*?\}\}").unwrap());
+
+const KNOWN_FILTERS: &[&str] = &[
+    "string",
+    "int",
+    "number",
+    "bool",
+    "boolean",
+    "upper",
+    "lower",
+    "trim",
+    "base64",
+    "unbase64",
+    "urlencode",
+    "json",
+    "length",
+    "sha256",
+    "date",
+    "add",
+    "mul",
+];
+
+// Block-capable engines behind `body_type: "template"`
+static ENGINE: Lazy<Handlebars<'static>> = Lazy::new(|| build_engine(false));
+static STRICT_ENGINE: Lazy<Handlebars<'static>> = Lazy::new(|| build_engine(true));
+
+fn build_engine(strict: bool) -> Handlebars<'static> {
+    let mut engine = Handlebars::new();
+    engine.set_strict_mode(strict);
+    // Mock bodies are JSON or text, not HTML
+    engine.register_escape_fn(handlebars::no_escape);
+    engine.register_helper("default", Box::new(default_helper));
+    engine.register_helper("json", Box::new(json_helper));
+    for name in ["now", "uuid", "randomInt", "randomString", "faker"] {
+        engine.register_helper(name, Box::new(dynamic_helper));
+    }
+    engine
+}
+
+// Number of requests served per expectation, backing {{counter}}
+static COUNTERS: Lazy<Mutex<HashMap<u64, u64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Increments and returns the request counter of an expectation.
+pub fn next_counter(expectation_id: u64) -> u64 {
+    let mut counters = COUNTERS.lock().unwrap();
+    let counter = counters.entry(expectation_id).or_insert(0);
+    *counter += 1;
+    *counter
+}
+
+/// A kind of `{{...}}` marker: how to find it, how to look up its value and
+/// which capture group holds its filter chain.
+struct Marker {
+    re: &'static Lazy<Regex>,
+    lookup: fn(&regex::Captures, &RequestContext) -> Option<Value>,
+    filters: usize,
+    /// Whether arrays and objects are interpolated as JSON rather than with
+    /// their quotes stripped
+    json: bool,
+}
+
+static MARKERS: [Marker; 6] = [
+    // {{path[0]}}, {{path[1]}}, etc.
+    Marker {
+        re: &PATH_RE,
+        lookup: |caps, ctx| {
+            let index: usize = caps[1].parse().ok()?;
+            ctx.path_segments
+                .get(index)
+                .map(|s| attempt_parse_string(s))
+        },
+        filters: 2,
+        json: false,
+    },
+    // {{body.some.field}} or {{body[0].name}}
+    Marker {
+        re: &BODY_RE,
+        lookup: |caps, ctx| get_value_by_path(ctx.body.as_ref()?, &caps[1]).cloned(),
+        filters: 2,
+        json: false,
+    },
+    // {{$.items[-1].id}}, {{body['content-type']}}, anything JSONPath allows
+    Marker {
+        re: &JSONPATH_RE,
+        lookup: |caps, ctx| query_json_path(ctx.body.as_ref()?, &caps[2]),
+        filters: 3,
+        json: true,
+    },
+    // {{headers.x-request-id}}, {{query.page}}, {{cookies.session}}
+    Marker {
+        re: &MAP_RE,
+        lookup: |caps, ctx| map_value(ctx, &caps[1], &caps[2]).map(attempt_parse_string),
+        filters: 3,
+        json: false,
+    },
+    // {{method}}, {{path}}, {{rawBody}} are always strings
+    Marker {
+        re: &REQUEST_RE,
+        lookup: |caps, ctx| Some(Value::String(request_value(ctx, &caps[1]).to_string())),
+        filters: 2,
+        json: false,
+    },
+    // {{uuid}}, {{randomInt 1 100}}, {{now "%Y-%m-%d" "+1d"}}, etc.
+    Marker {
+        re: &HELPER_RE,
+        lookup: |caps, ctx| eval_helper(&caps[1], &helper_args(&caps[2]), ctx.counter),
+        filters: 3,
+        json: false,
+    },
+];
+
+pub fn resolve_template(template: &str, ctx: &RequestContext) -> Result<String, String> {
+    if ctx.strict_templates
+        && let Some(unknown) = unknown_marker(template)
+    {
+        return Err(format!("unknown template reference {}", unknown));
+    }
+
+    let mut resolved = String::with_capacity(template.len());
+    let mut last = 0;
+    for (marker, caps) in find_markers(template) {
+        let whole = caps.get(0).unwrap();
+        resolved.push_str(&template[last..whole.start()]);
+        last = whole.end();
+
+        let chain = caps.get(marker.filters).map_or("", |m| m.as_str());
+        let val = match (marker.lookup)(&caps, ctx) {
+            Some(v) => apply_filters(v, chain)
+                .map(|v| to_interpolated(v, marker.json))
+                .map_err(|e| format!("{} in {}", e, &caps[0]))?,
+            None if ctx.strict_templates => {
+                return Err(format!("unresolved reference {}", &caps[0]));
+            }
+            None => "null".to_string(),
+        };
+        tracing::trace!("Template resolved {}: {}", &caps[0], val);
+        resolved.push_str(&val);
+    }
+    resolved.push_str(&template[last..]);
 
     tracing::trace!("Final resolved template: {}", resolved);
-    resolved
+    Ok(resolved)
+}
+
+/// The markers of a template from left to right, where a marker found by
+/// several kinds goes to the first of [`MARKERS`]. Markers are only looked
+/// for in the template itself, so request data substituted into it is
+/// never expanded in turn.
+fn find_markers(template: &str) -> Vec<(&'static Marker, regex::Captures<'_>)> {
+    let mut found = Vec::new();
+    let mut pos = 0;
+    while let Some((marker, caps)) = MARKERS
+        .iter()
+        .filter_map(|marker| marker.re.captures_at(template, pos).map(|c| (marker, c)))
+        .min_by_key(|(_, caps)| caps.get(0).unwrap().start())
+    {
+        pos = caps.get(0).unwrap().end();
+        found.push((marker, caps));
+    }
+    found
 }
 
-pub fn resolve_template_value(
-    res_body: Value,
-    path_segments: &[&str],
-    req_body: &Option<Value>,
-) -> Value {
+/// Renders a full template with blocks such as `{{#if}}` and `{{#each}}`
+/// against the request context. Missing values render as empty strings,
+/// or fail the render in strict mode.
+pub fn render_template(template: &str, ctx: &RequestContext) -> Result<String, String> {
+    let engine = if ctx.strict_templates {
+        &STRICT_ENGINE
+    } else {
+        &ENGINE
+    };
+    engine
+        .render_template(template, ctx)
+        .map_err(|e| e.to_string())
+}
+
+pub fn resolve_template_value(res_body: Value, ctx: &RequestContext) -> Result<Value, String> {
     match res_body {
         Value::String(s) => {
             // Check if it's a single template marker
-            if let Some(caps) = PATH_RE.captures(&s)
-                && caps[0] == s
-            {
-                let index: usize = caps[1].parse().unwrap_or(999);
-                let filter = caps.get(2).map(|m| m.as_str());
-                let raw_val = path_segments.get(index).cloned().unwrap_or("null");
-
-                return apply_filter(attempt_parse_string(raw_val), filter);
-            }
-            if let Some(caps) = BODY_RE.captures(&s)
-                && caps[0] == s
-            {
-                let path = &caps[1];
-                let filter = caps.get(2).map(|m| m.as_str());
-                if let Some(body_val) = req_body
-                    && let Some(v) = get_value_by_path(body_val, path)
+            for marker in MARKERS.iter() {
+                if let Some(caps) = marker.re.captures(&s)
+                    && caps[0] == s
                 {
-                    return apply_filter(v.clone(), filter);
+                    let chain = caps.get(marker.filters).map_or("", |m| m.as_str());
+                    return match (marker.lookup)(&caps, ctx) {
+                        Some(v) => apply_filters(v, chain).map_err(|e| format!("{} in {}", e, s)),
+                        None if ctx.strict_templates => Err(format!("unresolved reference {}", s)),
+                        None => Ok(Value::Null),
+                    };
                 }
-                return Value::Null;
             }
             // Fallback to string-based partial resolution
-            Value::String(resolve_template(&s, path_segments, req_body))
-        }
-        Value::Array(arr) => Value::Array(
-            arr.into_iter()
-                .map(|v| resolve_template_value(v, path_segments, req_body))
-                .collect(),
-        ),
-        Value::Object(obj) => Value::Object(
-            obj.into_iter()
-                .map(|(k, v)| (k, resolve_template_value(v, path_segments, req_body)))
-                .collect(),
-        ),
-        _ => res_body,
-    }
-}
-
-fn apply_filter(val: Value, filter: Option<&str>) -> Value {
-    match filter {
-        Some("string") => Value::String(match val {
-            Value::String(s) => s,
-            _ => val.to_string(),
-        }),
-        Some("int") | Some("number") => match val {
+            resolve_template(&s, ctx).map(Value::String)
+        }
+        Value::Array(arr) => arr
+            .into_iter()
+            .map(|v| resolve_template_value(v, ctx))
+            .collect::<Result<_, _>>()
+            .map(Value::Array),
+        Value::Object(obj) => obj
+            .into_iter()
+            .map(|(k, v)| resolve_template_value(v, ctx).map(|v| (k, v)))
+            .collect::<Result<_, _>>()
+            .map(Value::Object),
+        _ => Ok(res_body),
+    }
+}
+
+/// Applies a chain of filters such as `:lower:trim` from left to right.
+fn apply_filters(mut val: Value, chain: &str) -> Result<Value, String> {
+    for (filter, arg) in parse_filters(chain)? {
+        val = apply_filter(val, filter, arg)?;
+    }
+    Ok(val)
+}
+
+/// Splits a filter chain into filters and their arguments.
+fn parse_filters(chain: &str) -> Result<Vec<(&str, Option<&str>)>, String> {
+    let mut tokens = FILTER_TOKEN_RE.captures_iter(chain).map(|c| {
+        c.get(1)
+            .or(c.get(2))
+            .or(c.get(3))
+            .map_or("", |m| m.as_str())
+    });
+    let mut filters = Vec::new();
+    while let Some(filter) = tokens.next() {
+        if !KNOWN_FILTERS.contains(&filter) {
+            return Err(format!("unknown filter '{}'", filter));
+        }
+        let arg = if matches!(filter, "date" | "add" | "mul") {
+            Some(
+                tokens
+                    .next()
+                    .ok_or_else(|| format!("filter '{}' requires an argument", filter))?,
+            )
+        } else {
+            None
+        };
+        if let (Some(format), "date") = (arg, filter)
+            && !is_valid_date_format(format)
+        {
+            return Err(format!("invalid date format '{}'", format));
+        }
+        filters.push((filter, arg));
+    }
+    Ok(filters)
+}
+
+/// First `{{...}}` in the template that is not a known marker.
+fn unknown_marker(template: &str) -> Option<&str> {
+    ANY_MARKER_RE
+        .find_iter(template)
+        .map(|m| m.as_str())
+        .find(|candidate| {
+            !MARKERS.iter().any(|marker| {
+                marker
+                    .re
+                    .captures(candidate)
+                    .is_some_and(|caps| &caps[0] == *candidate)
+            })
+        })
+}
+
+/// Checks the filter chains, JSONPaths and helper arguments of the markers
+/// in a template, without resolving anything. Other `{{...}}` text is left
+/// alone unless `strict`, as it may be meant literally.
+pub fn validate_template(template: &str, strict: bool) -> Result<(), String> {
+    if strict && let Some(unknown) = unknown_marker(template) {
+        return Err(format!("unknown template reference {}", unknown));
+    }
+    for caps in JSONPATH_RE.captures_iter(template) {
+        JsonPath::parse(&format!("${}", &caps[2]))
+            .map_err(|e| format!("invalid JSONPath in {}: {}", &caps[0], e))?;
+    }
+    for marker in MARKERS.iter() {
+        for caps in marker.re.captures_iter(template) {
+            let chain = caps.get(marker.filters).map_or("", |m| m.as_str());
+            parse_filters(chain).map_err(|e| format!("{} in {}", e, &caps[0]))?;
+        }
+    }
+    for caps in HELPER_RE.captures_iter(template) {
+        if &caps[1] == "now" {
+            validate_now_args(&helper_args(&caps[2]))
+                .map_err(|e| format!("{} in {}", e, &caps[0]))?;
+        }
+    }
+    Ok(())
+}
+
+fn validate_now_args(args: &[&str]) -> Result<(), String> {
+    if let Some(format) = args.first()
+        && !matches!(*format, "iso" | "epoch" | "epoch_ms")
+        && !is_valid_date_format(format)
+    {
+        return Err(format!("invalid date format '{}'", format));
+    }
+    if let Some(offset) = args.get(1)
+        && parse_offset(offset)
+            .and_then(chrono::Duration::try_seconds)
+            .is_none()
+    {
+        return Err(format!("invalid offset '{}'", offset));
+    }
+    Ok(())
+}
+
+/// Checks the status, headers and body templates of a response.
+pub fn validate_response(res_config: &ResponseConfig, strict: bool) -> Result<(), String> {
+    if let Some(ResponseStatus::Template(ref template)) = res_config.status_code {
+        validate_template(template, strict).map_err(|e| format!("status_code: {}", e))?;
+    }
+    for (name, value) in res_config.headers.iter().flatten() {
+        if axum::http::HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err(format!("invalid header name '{}'", name));
+        }
+        validate_template(value, strict).map_err(|e| format!("header {}: {}", name, e))?;
+    }
+    let Some(ref body) = res_config.body else {
+        return Ok(());
+    };
+    if res_config.body_type.as_deref() == Some("template") {
+        let template = match body {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        return handlebars::Template::compile(&template)
+            .map(|_| ())
+            .map_err(|e| format!("body: {}", e));
+    }
+    validate_value(body, strict).map_err(|e| format!("body: {}", e))
+}
+
+fn validate_value(val: &Value, strict: bool) -> Result<(), String> {
+    match val {
+        Value::String(s) => validate_template(s, strict),
+        Value::Array(arr) => arr.iter().try_for_each(|v| validate_value(v, strict)),
+        Value::Object(obj) => obj.values().try_for_each(|v| validate_value(v, strict)),
+        _ => Ok(()),
+    }
+}
+
+/// Whether a template may read the request body. Errs on the side of yes.
+pub fn references_body(template: &str) -> bool {
+    template.contains("body") || template.contains("Body") || template.contains("{{$")
+}
+
+/// Checks every response a mock can produce, including jitter outcomes.
+/// `strict_templates` is the global setting, which the mock can override.
+pub fn validate_mock(response: &MockResponse, strict_templates: bool) -> Result<(), String> {
+    let strict = response.strict_templates.unwrap_or(strict_templates);
+    validate_response(&response.response, strict)?;
+    for outcome in response.jitter.iter().flat_map(|j| j.outcomes()) {
+        validate_response(&outcome.response, strict).map_err(|e| format!("jitter: {}", e))?;
+    }
+    if let Some(ref proxy) = response.proxy {
+        crate::proxy::validate(proxy, strict).map_err(|e| format!("proxy: {}", e))?;
+    }
+    if let Some(ref shadow) = response.shadow {
+        crate::proxy::validate(&shadow.upstream, strict)
+            .and_then(|_| crate::shadow::validate(shadow))
+            .map_err(|e| format!("shadow: {}", e))?;
+    }
+    for callback in response.callbacks.iter().flatten() {
+        let context = |e| format!("callback {}: {}", callback.url, e);
+        validate_template(&callback.url, strict).map_err(context)?;
+        for value in callback.headers.iter().flat_map(|h| h.values()) {
+            validate_template(value, strict).map_err(context)?;
+        }
+        if let Some(ref body) = callback.body {
+            validate_value(body, strict).map_err(context)?;
+        }
+    }
+    Ok(())
+}
+
+/// Text form of a value when it is interpolated into a larger string.
+fn to_interpolated(val: Value, json: bool) -> String {
+    match val {
+        Value::String(s) => s,
+        Value::Array(_) | Value::Object(_) if json => val.to_string(),
+        _ => val.to_string().replace("\"", ""),
+    }
+}
+
+fn to_text(val: &Value) -> String {
+    match val {
+        Value::String(s) => s.clone(),
+        _ => val.to_string(),
+    }
+}
+
+fn apply_filter(val: Value, filter: &str, arg: Option<&str>) -> Result<Value, String> {
+    Ok(match filter {
+        "string" => Value::String(to_text(&val)),
+        "int" | "number" => match val {
             Value::Number(_) => val,
             Value::String(ref s) => {
                 if let Ok(i) = s.parse::<i64>() {
@@ -113,7 +469,7 @@ fn apply_filter(val: Value, filter: Option<&str>) -> Value {
             }
             _ => val,
         },
-        Some("bool") | Some("boolean") => match val {
+        "bool" | "boolean" => match val {
             Value::Bool(_) => val,
             Value::String(ref s) => {
                 if s == "true" {
@@ -126,7 +482,97 @@ fn apply_filter(val: Value, filter: Option<&str>) -> Value {
             }
             _ => val,
         },
-        _ => val,
+        "upper" => Value::String(to_text(&val).to_uppercase()),
+        "lower" => Value::String(to_text(&val).to_lowercase()),
+        "trim" => Value::String(to_text(&val).trim().to_string()),
+        "base64" => Value::String(BASE64.encode(to_text(&val))),
+        "unbase64" => {
+            let decoded = BASE64
+                .decode(to_text(&val).trim())
+                .map_err(|e| format!("filter 'unbase64' got invalid base64: {}", e))?;
+            Value::String(String::from_utf8_lossy(&decoded).into_owned())
+        }
+        "urlencode" => Value::String(urlencoding::encode(&to_text(&val)).into_owned()),
+        "json" => Value::String(val.to_string()),
+        "length" => {
+            let len = match val {
+                Value::String(ref s) => s.chars().count(),
+                Value::Array(ref a) => a.len(),
+                Value::Object(ref o) => o.len(),
+                Value::Null => 0,
+                _ => to_text(&val).chars().count(),
+            };
+            Value::Number(len.into())
+        }
+        "sha256" => Value::String(format!("{:x}", Sha256::digest(to_text(&val)))),
+        "date" => {
+            let format = arg.unwrap_or_default();
+            let date = parse_date(&val)
+                .ok_or_else(|| format!("filter 'date' cannot parse {} as a date", val))?;
+            Value::String(
+                format_date(&date, format)
+                    .ok_or_else(|| format!("filter 'date' has an invalid format '{}'", format))?,
+            )
+        }
+        "add" | "mul" => {
+            let arg = arg.unwrap_or_default();
+            let operand = attempt_parse_string(arg);
+            let operand = operand.as_number().ok_or_else(|| {
+                format!("filter '{}' needs a numeric argument, got {}", filter, arg)
+            })?;
+            let value = match val {
+                Value::Number(ref n) => n.clone(),
+                Value::String(ref s) => match attempt_parse_string(s) {
+                    Value::Number(n) => n,
+                    _ => {
+                        return Err(format!(
+                            "filter '{}' cannot use {} as a number",
+                            filter, val
+                        ));
+                    }
+                },
+                _ => {
+                    return Err(format!(
+                        "filter '{}' cannot use {} as a number",
+                        filter, val
+                    ));
+                }
+            };
+            arithmetic(filter, &value, operand)
+                .ok_or_else(|| format!("filter '{}' overflowed", filter))?
+        }
+        _ => return Err(format!("unknown filter '{}'", filter)),
+    })
+}
+
+fn arithmetic(op: &str, a: &serde_json::Number, b: &serde_json::Number) -> Option<Value> {
+    if let (Some(a), Some(b)) = (a.as_i64(), b.as_i64()) {
+        let result = if op == "add" {
+            a.checked_add(b)
+        } else {
+            a.checked_mul(b)
+        };
+        return result.map(|r| Value::Number(r.into()));
+    }
+    let (a, b) = (a.as_f64()?, b.as_f64()?);
+    let result = if op == "add" { a + b } else { a * b };
+    serde_json::Number::from_f64(result).map(Value::Number)
+}
+
+/// Accepts RFC 3339 timestamps, plain dates and unix timestamps in seconds.
+fn parse_date(val: &Value) -> Option<chrono::DateTime<chrono::Utc>> {
+    match val {
+        Value::Number(n) => chrono::DateTime::from_timestamp(n.as_i64()?, 0),
+        Value::String(s) => {
+            if let Ok(date) = chrono::DateTime::parse_from_rfc3339(s) {
+                return Some(date.with_timezone(&chrono::Utc));
+            }
+            if let Ok(date) = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+                return Some(date.and_hms_opt(0, 0, 0)?.and_utc());
+            }
+            chrono::DateTime::from_timestamp(s.parse().ok()?, 0)
+        }
+        _ => None,
     }
 }
 
@@ -164,3 +610,224 @@ fn get_value_by_path<'a>(body: &'a Value, path: &str) -> Option<&'a Value> {
     }
     Some(current)
 }
+
+/// Runs a JSONPath query (without its leading `$`) against the body. A
+/// single match is returned as is, several matches as an array.
+fn query_json_path(body: &Value, path: &str) -> Option<Value> {
+    let path = match JsonPath::parse(&format!("${}", path)) {
+        Ok(path) => path,
+        Err(e) => {
+            tracing::warn!("Invalid JSONPath ${}: {}", path, e);
+            return None;
+        }
+    };
+    let mut nodes = path.query(body).all();
+    match nodes.len() {
+        0 => None,
+        1 => nodes.pop().cloned(),
+        _ => Some(Value::Array(nodes.into_iter().cloned().collect())),
+    }
+}
+
+fn map_value<'a>(ctx: &'a RequestContext, map: &str, key: &str) -> Option<&'a str> {
+    let values = match map {
+        "headers" => return ctx.headers.get(&key.to_lowercase()).map(String::as_str),
+        "query" => &ctx.query,
+        "cookies" => &ctx.cookies,
+        _ => return None,
+    };
+    values.get(key).map(String::as_str)
+}
+
+fn request_value<'a>(ctx: &'a RequestContext, attribute: &str) -> &'a str {
+    match attribute {
+        "method" => &ctx.method,
+        "path" => &ctx.path,
+        _ => &ctx.raw_body,
+    }
+}
+
+fn helper_args(raw_args: &str) -> Vec<&str> {
+    HELPER_ARG_RE
+        .captures_iter(raw_args)
+        .filter_map(|c| c.get(1).or(c.get(2)).or(c.get(3)))
+        .map(|m| m.as_str())
+        .collect()
+}
+
+fn eval_helper(helper: &str, args: &[&str], counter: u64) -> Option<Value> {
+    match helper {
+        "now" => return now_helper(args),
+        "counter" => return Some(Value::Number(counter.into())),
+        _ => {}
+    }
+    crate::rng::with_rng(|rng| match helper {
+        "uuid" => {
+            let uuid = uuid::Builder::from_random_bytes(rng.random()).into_uuid();
+            Some(Value::String(uuid.to_string()))
+        }
+        "randomInt" => {
+            let min: i64 = args.first().map(|a| a.parse().ok()).unwrap_or(Some(0))?;
+            let max: i64 = args.get(1).map(|a| a.parse().ok()).unwrap_or(Some(100))?;
+            let val = if max <= min {
+                min
+            } else {
+                rng.random_range(min..=max)
+            };
+            Some(Value::Number(val.into()))
+        }
+        "randomString" => {
+            let len: usize = args.first().map(|a| a.parse().ok()).unwrap_or(Some(16))?;
+            let val: String = (0..len).map(|_| rng.sample(Alphanumeric) as char).collect();
+            Some(Value::String(val))
+        }
+        "faker.name" => Some(Value::String(name::Name().fake_with_rng(rng))),
+        "faker.firstName" => Some(Value::String(name::FirstName().fake_with_rng(rng))),
+        "faker.lastName" => Some(Value::String(name::LastName().fake_with_rng(rng))),
+        "faker.email" => Some(Value::String(internet::SafeEmail().fake_with_rng(rng))),
+        "faker.company" => Some(Value::String(company::CompanyName().fake_with_rng(rng))),
+        "faker.address" => {
+            let number: String = address::BuildingNumber().fake_with_rng(rng);
+            let street: String = address::StreetName().fake_with_rng(rng);
+            let city: String = address::CityName().fake_with_rng(rng);
+            Some(Value::String(format!("{} {}, {}", number, street, city)))
+        }
+        "faker.lorem" => {
+            let words: usize = args.first().map(|a| a.parse().ok()).unwrap_or(Some(8))?;
+            let words: Vec<String> = lorem::Words(words..words + 1).fake_with_rng(rng);
+            Some(Value::String(words.join(" ")))
+        }
+        _ => {
+            tracing::warn!("Unknown template helper: {}", helper);
+            None
+        }
+    })
+}
+
+/// `{{now}}`, `{{now "epoch"}}` or `{{now "%Y-%m-%d" "+1d"}}`. Invalid
+/// formats and offsets out of range resolve to nothing.
+fn now_helper(args: &[&str]) -> Option<Value> {
+    let offset = args.get(1).map(|o| parse_offset(o)).unwrap_or(Some(0))?;
+    let now = chrono::Utc::now().checked_add_signed(chrono::Duration::try_seconds(offset)?)?;
+    Some(match args.first().copied() {
+        None | Some("iso") => Value::String(now.to_rfc3339()),
+        Some("epoch") => Value::Number(now.timestamp().into()),
+        Some("epoch_ms") => Value::Number(now.timestamp_millis().into()),
+        Some(format) => Value::String(format_date(&now, format)?),
+    })
+}
+
+/// Formats a date with a strftime format, `None` if the format is invalid.
+fn format_date(date: &chrono::DateTime<chrono::Utc>, format: &str) -> Option<String> {
+    let mut formatted = String::new();
+    write!(formatted, "{}", date.format(format)).ok()?;
+    Some(formatted)
+}
+
+fn is_valid_date_format(format: &str) -> bool {
+    !StrftimeItems::new(format).any(|item| matches!(item, Item::Error))
+}
+
+/// Parses offsets like `+1d`, `-30m` or `15s` into seconds.
+fn parse_offset(offset: &str) -> Option<i64> {
+    let (sign, rest) = match offset.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, offset.strip_prefix('+').unwrap_or(offset)),
+    };
+    let unit = rest.chars().last()?;
+    let (amount, multiplier) = if unit.is_ascii_digit() {
+        (rest, 1)
+    } else {
+        let multiplier = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return None,
+        };
+        (&rest[..rest.len() - 1], multiplier)
+    };
+    amount.parse::<i64>().ok()?.checked_mul(sign * multiplier)
+}
+
+/// `{{default body.name "anon"}}`: the first argument unless it is missing,
+/// null or empty, otherwise the fallback.
+fn default_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let value = h
+        .param(0)
+        .map(|p| p.value())
+        .filter(|v| !v.is_null() && v.as_str() != Some(""))
+        .or_else(|| h.param(1).map(|p| p.value()));
+    if let Some(value) = value {
+        out.write(&value_to_text(value))?;
+    }
+    Ok(())
+}
+
+/// `{{json body.items}}`: embeds a value as JSON.
+fn json_helper(
+    h: &Helper,
+    r: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let param = h.param(0);
+    if r.strict_mode()
+        && let Some(param) = param
+        && param.is_value_missing()
+    {
+        return Err(handlebars::RenderError::strict_error(param.relative_path()));
+    }
+    let value = param.map(|p| p.value()).unwrap_or(&Value::Null);
+    out.write(&value.to_string())?;
+    Ok(())
+}
+
+/// Exposes the dynamic helpers, e.g. `{{randomInt 1 6}}` or `{{faker "email"}}`.
+fn dynamic_helper(
+    h: &Helper,
+    _: &Handlebars,
+    ctx: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let mut args: Vec<String> = h
+        .params()
+        .iter()
+        .map(|p| value_to_text(p.value()))
+        .collect();
+    let name = if h.name() == "faker" {
+        if args.is_empty() {
+            return Err(RenderErrorReason::ParamNotFoundForIndex("faker", 0).into());
+        }
+        format!("faker.{}", args.remove(0))
+    } else {
+        h.name().to_string()
+    };
+    let counter = ctx
+        .data()
+        .get("counter")
+        .and_then(Value::as_u64)
+        .unwrap_or(0);
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    if let Some(value) = eval_helper(&name, &args, counter) {
+        out.write(&value_to_text(&value))?;
+    }
+    Ok(())
+}
+
+fn value_to_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
@@ -46,10 +46,9 @@ async fn wait_for_server(base_url: &str) {
             .get(format!("{}/_admin/mocks", base_url))
             .send()
             .await
+            && res.status() == 200
         {
-            if res.status() == 200 {
-                return;
-            }
+            return;
         }
         sleep(Duration::from_millis(500)).await;
     }
@@ -10,6 +10,10 @@ struct TestServer {
 
 impl TestServer {
     fn start(port: Option<u16>, expectations: Option<&str>) -> Self {
+        Self::start_with_args(port, expectations, &[])
+    }
+
+    fn start_with_args(port: Option<u16>, expectations: Option<&str>, args: &[&str]) -> Self {
         let mut cmd = Command::new("target/debug/mimicrab");
         if let Some(p) = port {
             cmd.arg("--port").arg(p.to_string());
@@ -17,6 +21,7 @@ impl TestServer {
         if let Some(e) = expectations {
             cmd.arg("--expectations").arg(e);
         }
+        cmd.args(args);
         let child = cmd
             .stdout(Stdio::inherit())
             .stderr(Stdio::inherit())
@@ -50,10 +55,9 @@ async fn wait_for_server(base_url: &str) {
             .get(format!("{}/_admin/mocks", base_url))
             .send()
             .await
+            && res.status() == 200
         {
-            if res.status() == 200 {
-                return;
-            }
+            return;
         }
         sleep(Duration::from_millis(500)).await;
     }
@@ -244,6 +248,85 @@ async fn test_mock_latency() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_mock_latency_distribution() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 3024;
+    let _server = TestServer::start(Some(port), Some("expectations_latency_dist.json"));
+    let base_url = format!("http://localhost:{}", port);
+    wait_for_server(&base_url).await;
+
+    let client = reqwest::Client::new();
+    let admin_url = format!("{}/_admin/mocks", base_url);
+
+    // Uniform distribution between 200ms and 400ms
+    let new_mock = json!({
+        "id": 777124,
+        "condition": {
+            "method": "GET",
+            "path": "/latency-dist-test"
+        },
+        "response": {
+            "status_code": 200,
+            "body": { "delayed": true },
+            "latency": { "min": 200, "max": 400 }
+        }
+    });
+
+    let res = client.post(&admin_url).json(&new_mock).send().await?;
+    assert_eq!(res.status(), 201);
+
+    let start = std::time::Instant::now();
+    let res = client
+        .get(format!("{}/latency-dist-test", base_url))
+        .send()
+        .await?;
+    let duration = start.elapsed();
+
+    assert_eq!(res.status(), 200);
+    assert!(
+        duration >= Duration::from_millis(200),
+        "Response was too fast: {:?}",
+        duration
+    );
+
+    // Percentile tables and log-normal settings are accepted as well
+    let res = client
+        .put(format!("{}/{}", admin_url, 777124))
+        .json(&json!({
+            "id": 777124,
+            "condition": { "method": "GET", "path": "/latency-dist-test" },
+            "response": {
+                "status_code": 200,
+                "latency": { "mean": 20.0, "stddev": 5.0, "distribution": "lognormal" },
+                "jitter": {
+                    "probability": 0.0,
+                    "latency": { "p50": 10, "p95": 50, "p99": 100 }
+                }
+            }
+        }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 200);
+
+    let res = client
+        .get(format!("{}/latency-dist-test", base_url))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 200);
+
+    let body = client
+        .get(format!("{}/_admin/metrics", base_url))
+        .send()
+        .await?
+        .text()
+        .await?;
+    assert!(
+        body.contains("mimicrab_injected_latency_seconds_count{path=\"/latency-dist-test\"} 2")
+    );
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_mock_jitter() -> Result<(), Box<dyn std::error::Error>> {
     let port = 3023;
@@ -319,6 +402,125 @@ async fn test_mock_jitter() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_mock_weighted_jitter() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 3026;
+    let _server = TestServer::start(Some(port), Some("expectations_weighted_jitter.json"));
+    let base_url = format!("http://localhost:{}", port);
+    wait_for_server(&base_url).await;
+
+    let client = reqwest::Client::new();
+
+    client
+        .post(format!("{}/_admin/mocks", base_url))
+        .json(&json!({
+            "id": 555124,
+            "condition": { "method": "GET", "path": "/weighted-jitter-test" },
+            "response": {
+                "status_code": 200,
+                "body": { "ok": true },
+                "jitter": [
+                    { "probability": 0.3, "status_code": 500 },
+                    { "probability": 0.3, "status_code": 503 }
+                ]
+            }
+        }))
+        .send()
+        .await?;
+
+    let mut runs = Vec::new();
+    for _ in 0..2 {
+        let res = client
+            .post(format!("{}/_admin/seed", base_url))
+            .json(&json!({ "seed": 42 }))
+            .send()
+            .await?;
+        assert_eq!(res.status(), 200);
+
+        let mut statuses = Vec::new();
+        for _ in 0..30 {
+            let res = client
+                .get(format!("{}/weighted-jitter-test", base_url))
+                .send()
+                .await?;
+            statuses.push(res.status().as_u16());
+        }
+        runs.push(statuses);
+    }
+
+    // The same seed replays the same sequence of outcomes
+    assert_eq!(runs[0], runs[1]);
+    for status in [200, 500, 503] {
+        assert!(runs[0].contains(&status), "Missing status {}", status);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mock_faults() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 3025;
+    let _server = TestServer::start(Some(port), Some("expectations_faults.json"));
+    let base_url = format!("http://localhost:{}", port);
+    wait_for_server(&base_url).await;
+
+    let client = reqwest::Client::new();
+    let admin_url = format!("{}/_admin/mocks", base_url);
+
+    let mocks = [
+        ("/fault-reset", json!({ "fault": "connection_reset" })),
+        (
+            "/fault-truncated",
+            json!({ "status_code": 200, "body": { "data": "0123456789" }, "fault": "truncated_body" }),
+        ),
+        ("/fault-garbage", json!({ "fault": "garbage" })),
+        (
+            "/fault-chunk",
+            json!({ "body": { "ok": true }, "fault": "malformed_chunk" }),
+        ),
+        (
+            "/fault-jitter",
+            json!({
+                "status_code": 200,
+                "body": { "ok": true },
+                "jitter": { "probability": 1.0, "fault": "close_after_headers" }
+            }),
+        ),
+    ];
+    for (i, (path, response)) in mocks.iter().enumerate() {
+        let res = client
+            .post(&admin_url)
+            .json(&json!({
+                "id": 444000 + i as u64,
+                "condition": { "method": "GET", "path": path },
+                "response": response
+            }))
+            .send()
+            .await?;
+        assert_eq!(res.status(), 201);
+    }
+
+    // Faults either break the request or the body read
+    for (path, _) in mocks.iter() {
+        let result = match client.get(format!("{}{}", base_url, path)).send().await {
+            Ok(res) => res.bytes().await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+        assert!(result.is_err(), "Expected a failure for {}", path);
+    }
+
+    // The server keeps working for other connections
+    let res = client
+        .get(format!("{}/_admin/metrics", base_url))
+        .send()
+        .await?;
+    let body = res.text().await?;
+    assert!(body.contains("mimicrab_faults_injected_total{kind=\"connection_reset\"} 1"));
+    assert!(body.contains("mimicrab_faults_injected_total{kind=\"close_after_headers\"} 1"));
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_mock_proxy() -> Result<(), Box<dyn std::error::Error>> {
     // 1. Start Upstream on 3001
@@ -584,3 +786,2039 @@ async fn test_metrics() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_content_negotiation() -> Result<(), Box<dyn std::error::Error>> {
+    let _server = TestServer::start(Some(3032), Some("expectations_negotiation.json"));
+    let base_url = "http://localhost:3032";
+    wait_for_server(base_url).await;
+
+    let client = reqwest::Client::new();
+    let user = json!({ "user": { "@id": "7", "name": "Ada & Co", "roles": ["admin", "dev"], "team": null } });
+
+    client
+        .post(format!("{}/_admin/mocks", base_url))
+        .json(&json!({
+            "id": 1,
+            "condition": { "path": "/user" },
+            "response": { "status_code": 200, "body": user }
+        }))
+        .send()
+        .await?;
+    client
+        .post(format!("{}/_admin/mocks", base_url))
+        .json(&json!({
+            "id": 2,
+            "condition": { "path": "/fixed" },
+            "response": { "status_code": 200, "body_type": "yaml", "negotiate": false, "body": user }
+        }))
+        .send()
+        .await?;
+
+    let get = |path: &str, accept: Option<&str>| {
+        let mut req = client.get(format!("{}{}", base_url, path));
+        if let Some(accept) = accept {
+            req = req.header("Accept", accept);
+        }
+        req.send()
+    };
+    let content_type =
+        |res: &reqwest::Response| res.headers()["content-type"].to_str().unwrap().to_string();
+
+    // JSON stays the default
+    let res = get("/user", None).await?;
+    assert_eq!(content_type(&res), "application/json");
+    assert_eq!(res.json::<Value>().await?, user);
+
+    let res = get("/user", Some("application/xml")).await?;
+    assert_eq!(content_type(&res), "application/xml");
+    assert_eq!(res.headers()["vary"], "Accept");
+    assert_eq!(
+        res.text().await?,
+        r#"<?xml version="1.0" encoding="UTF-8"?><user id="7"><name>Ada &amp; Co</name><roles>admin</roles><roles>dev</roles><team/></user>"#
+    );
+
+    let res = get("/user", Some("application/yaml")).await?;
+    assert_eq!(content_type(&res), "application/yaml");
+    let yaml: Value = serde_yaml::from_str(&res.text().await?)?;
+    assert_eq!(yaml, user);
+
+    let res = get("/user", Some("application/msgpack")).await?;
+    assert_eq!(content_type(&res), "application/msgpack");
+    let msgpack: Value = rmp_serde::from_slice(&res.bytes().await?)?;
+    assert_eq!(msgpack, user);
+
+    let res = get("/user", Some("application/cbor")).await?;
+    assert_eq!(content_type(&res), "application/cbor");
+    let cbor: Value = ciborium::from_reader(&res.bytes().await?[..])?;
+    assert_eq!(cbor, user);
+
+    let res = get("/user", Some("application/bson")).await?;
+    assert_eq!(content_type(&res), "application/bson");
+    let bson: Value = bson::from_slice(&res.bytes().await?)?;
+    assert_eq!(bson, user);
+
+    // The mock's own format is kept when the client only accepts it
+    // through a wildcard, as browsers do...
+    let res = get(
+        "/user",
+        Some("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+    )
+    .await?;
+    assert_eq!(content_type(&res), "application/json");
+    let res = get(
+        "/user",
+        Some("application/xml;q=0.5, application/cbor;q=0.8, */*;q=0.1"),
+    )
+    .await?;
+    assert_eq!(content_type(&res), "application/json");
+
+    // ...or when it ties for the highest q-value
+    let res = get("/user", Some("application/cbor, application/json")).await?;
+    assert_eq!(content_type(&res), "application/json");
+
+    // Otherwise q-values decide between formats
+    let res = get("/user", Some("application/cbor, application/json;q=0.1")).await?;
+    assert_eq!(content_type(&res), "application/cbor");
+    let res = get(
+        "/user",
+        Some("application/xml;q=0.5, application/cbor;q=0.8"),
+    )
+    .await?;
+    assert_eq!(content_type(&res), "application/cbor");
+    let res = get("/user", Some("application/*;q=0.9, application/json;q=0")).await?;
+    assert_eq!(content_type(&res), "application/xml");
+    let res = get("/user", Some("text/html")).await?;
+    assert_eq!(content_type(&res), "application/json");
+
+    // Negotiation can be turned off to pin the body_type format
+    let res = get("/fixed", Some("application/json")).await?;
+    assert_eq!(content_type(&res), "application/yaml");
+    assert!(res.headers().get_all("vary").iter().all(|v| v != "Accept"));
+    let yaml: Value = serde_yaml::from_str(&res.text().await?)?;
+    assert_eq!(yaml, user);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_binary_and_compressed_request_bodies() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+
+    let _server = TestServer::start(Some(3033), Some("expectations_binary_bodies.json"));
+    let base_url = "http://localhost:3033";
+    wait_for_server(base_url).await;
+
+    let client = reqwest::Client::new();
+    let order = json!({ "kind": "order", "qty": 2 });
+
+    client
+        .post(format!("{}/_admin/mocks", base_url))
+        .json(&json!({
+            "id": 1,
+            "condition": { "method": "POST", "path": "/orders", "body": order },
+            "response": {
+                "status_code": 200,
+                "body": { "summary": "{{body.kind}} x{{body.qty}}" }
+            }
+        }))
+        .send()
+        .await?;
+    client
+        .post(format!("{}/_admin/mocks", base_url))
+        .json(&json!({
+            "id": 2,
+            "condition": { "method": "POST", "path": "/script" },
+            "response": {
+                "script": "return { status = 200, body = { total = request.body.qty * 10 } }"
+            }
+        }))
+        .send()
+        .await?;
+
+    let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gzip.write_all(order.to_string().as_bytes())?;
+    let gzip = gzip.finish()?;
+
+    let msgpack = rmp_serde::to_vec_named(&order)?;
+    let mut brotli_msgpack = Vec::new();
+    {
+        let mut writer = brotli::CompressorWriter::new(&mut brotli_msgpack, 4096, 5, 22);
+        writer.write_all(&msgpack)?;
+    }
+
+    let mut cbor = Vec::new();
+    ciborium::into_writer(&order, &mut cbor)?;
+    let mut deflate_cbor =
+        flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    deflate_cbor.write_all(&cbor)?;
+    let deflate_cbor = deflate_cbor.finish()?;
+
+    let mut bson = Vec::new();
+    bson::to_document(&order)?.to_writer(&mut bson)?;
+
+    let cases: Vec<(&str, Option<&str>, Vec<u8>)> = vec![
+        ("application/json", Some("gzip"), gzip),
+        ("application/msgpack", None, msgpack),
+        ("application/x-msgpack", Some("br"), brotli_msgpack),
+        ("application/cbor", None, cbor.clone()),
+        ("application/cbor", Some("deflate"), deflate_cbor),
+        ("application/bson", None, bson),
+    ];
+
+    for (content_type, encoding, bytes) in cases {
+        for path in ["/orders", "/script"] {
+            let mut req = client
+                .post(format!("{}{}", base_url, path))
+                .header("Content-Type", content_type)
+                .body(bytes.clone());
+            if let Some(encoding) = encoding {
+                req = req.header("Content-Encoding", encoding);
+            }
+            let res = req.send().await?;
+            assert_eq!(
+                res.status(),
+                200,
+                "{} {:?} {}",
+                content_type,
+                encoding,
+                path
+            );
+            let body: Value = res.json().await?;
+            if path == "/orders" {
+                assert_eq!(body["summary"], "order x2");
+            } else {
+                assert_eq!(body["total"], 20);
+            }
+        }
+    }
+
+    // Without a matching Content-Type binary bodies are not decoded
+    let res = client
+        .post(format!("{}/orders", base_url))
+        .header("Content-Type", "application/octet-stream")
+        .body(cbor)
+        .send()
+        .await?;
+    assert_eq!(res.status(), 404);
+
+    // Bodies that would decompress past the limit are refused
+    let mut bomb = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    bomb.write_all(&vec![0; 17 * 1024 * 1024])?;
+    let res = client
+        .post(format!("{}/orders", base_url))
+        .header("Content-Type", "application/json")
+        .header("Content-Encoding", "gzip")
+        .body(bomb.finish()?)
+        .send()
+        .await?;
+    assert_eq!(res.status(), 413);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_response_compression() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Read;
+
+    let _server = TestServer::start(Some(3034), Some("expectations_compression.json"));
+    let base_url = "http://localhost:3034";
+    wait_for_server(base_url).await;
+
+    let client = reqwest::Client::new();
+    let payload = json!({ "message": "hello ".repeat(50) });
+
+    for (id, path, extra) in [
+        (1, "/auto", json!({})),
+        (2, "/forced", json!({ "compression": "zstd" })),
+        (3, "/disabled", json!({ "compression": "none" })),
+        (
+            4,
+            "/wrong",
+            json!({ "compression": "gzip", "declared_encoding": "br" }),
+        ),
+    ] {
+        let mut response = json!({ "status_code": 200, "body": payload });
+        response
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        client
+            .post(format!("{}/_admin/mocks", base_url))
+            .json(&json!({ "id": id, "condition": { "path": path }, "response": response }))
+            .send()
+            .await?;
+    }
+
+    let fetch = |path: &str, accept_encoding: Option<&str>| {
+        let mut req = client.get(format!("{}{}", base_url, path));
+        if let Some(accept_encoding) = accept_encoding {
+            req = req.header("Accept-Encoding", accept_encoding);
+        }
+        req.send()
+    };
+    let encoding = |res: &reqwest::Response| {
+        res.headers()
+            .get("content-encoding")
+            .map(|v| v.to_str().unwrap().to_string())
+    };
+
+    // Without Accept-Encoding the body is sent as is
+    let res = fetch("/auto", None).await?;
+    assert_eq!(encoding(&res), None);
+    assert_eq!(res.json::<Value>().await?, payload);
+
+    let res = fetch("/auto", Some("gzip")).await?;
+    assert_eq!(encoding(&res).as_deref(), Some("gzip"));
+    assert_eq!(res.headers()["vary"], "Accept");
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(&res.bytes().await?[..]).read_to_string(&mut decoded)?;
+    assert_eq!(serde_json::from_str::<Value>(&decoded)?, payload);
+
+    // q-values pick the encoding
+    let res = fetch("/auto", Some("gzip;q=0.5, br;q=0.9, zstd;q=0")).await?;
+    assert_eq!(encoding(&res).as_deref(), Some("br"));
+    let mut decoded = Vec::new();
+    brotli::Decompressor::new(&res.bytes().await?[..], 4096).read_to_end(&mut decoded)?;
+    assert_eq!(serde_json::from_slice::<Value>(&decoded)?, payload);
+
+    let res = fetch("/auto", Some("deflate")).await?;
+    assert_eq!(encoding(&res).as_deref(), Some("deflate"));
+    let mut decoded = Vec::new();
+    flate2::read::ZlibDecoder::new(&res.bytes().await?[..]).read_to_end(&mut decoded)?;
+    assert_eq!(serde_json::from_slice::<Value>(&decoded)?, payload);
+
+    // Forced compression ignores what the client accepts
+    let res = fetch("/forced", None).await?;
+    assert_eq!(encoding(&res).as_deref(), Some("zstd"));
+    let decoded = zstd::decode_all(&res.bytes().await?[..])?;
+    assert_eq!(serde_json::from_slice::<Value>(&decoded)?, payload);
+
+    let res = fetch("/disabled", Some("gzip, br")).await?;
+    assert_eq!(encoding(&res), None);
+    assert_eq!(res.json::<Value>().await?, payload);
+
+    // The declared encoding does not match the body
+    let res = fetch("/wrong", None).await?;
+    assert_eq!(encoding(&res).as_deref(), Some("br"));
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(&res.bytes().await?[..]).read_to_string(&mut decoded)?;
+    assert_eq!(serde_json::from_str::<Value>(&decoded)?, payload);
+
+    Ok(())
+}
+
+/// Reads the next entry from a `/_admin/logs/stream` response.
+async fn next_log(stream: &mut reqwest::Response, buffer: &mut String) -> Value {
+    loop {
+        if let Some(end) = buffer.find("\n\n") {
+            let event: String = buffer.drain(..end + 2).collect();
+            if let Some(data) = event.lines().find_map(|l| l.strip_prefix("data:")) {
+                return serde_json::from_str(data.trim()).unwrap();
+            }
+            continue;
+        }
+        let chunk = tokio::time::timeout(Duration::from_secs(10), stream.chunk())
+            .await
+            .expect("timed out waiting for a log entry")
+            .unwrap()
+            .expect("log stream closed");
+        buffer.push_str(&String::from_utf8_lossy(&chunk));
+    }
+}
+
+#[tokio::test]
+async fn test_mock_callbacks() -> Result<(), Box<dyn std::error::Error>> {
+    let _receiver = TestServer::start(Some(3036), Some("expectations_callback_receiver.json"));
+    wait_for_server("http://localhost:3036").await;
+    let _primary = TestServer::start(Some(3035), Some("expectations_callback_primary.json"));
+    wait_for_server("http://localhost:3035").await;
+
+    let client = reqwest::Client::new();
+
+    // The receiver fails twice before accepting the callback
+    client
+        .post("http://localhost:3036/_admin/mocks")
+        .json(&json!({
+            "id": 1,
+            "condition": { "method": "POST", "path": "/hooks/payment" },
+            "response": {
+                "script": "local status = 200\nif request.counter < 3 then status = 503 end\nreturn { status = status }"
+            }
+        }))
+        .send()
+        .await?;
+
+    client
+        .post("http://localhost:3035/_admin/mocks")
+        .json(&json!({
+            "id": 1,
+            "condition": { "method": "POST", "path": "/payments" },
+            "response": {
+                "status_code": 202,
+                "body": { "status": "accepted" },
+                "callbacks": [{
+                    "url": "http://localhost:3036/hooks/payment",
+                    "headers": { "X-Payment-Id": "{{body.id}}" },
+                    "body": { "id": "{{body.id}}", "status": "paid", "amount": "{{body.amount}}" },
+                    "delay": 300,
+                    "retry": { "max_retries": 3, "backoff_ms": 50 }
+                }]
+            }
+        }))
+        .send()
+        .await?;
+    client
+        .post("http://localhost:3035/_admin/mocks")
+        .json(&json!({
+            "id": 2,
+            "condition": { "method": "POST", "path": "/broken" },
+            "response": {
+                "strict_templates": true,
+                "body": { "id": "{{body.missing}}" },
+                "callbacks": [{ "url": "http://localhost:3036/hooks/payment", "body": "broken" }]
+            }
+        }))
+        .send()
+        .await?;
+
+    let mut receiver_logs = client
+        .get("http://localhost:3036/_admin/logs/stream")
+        .send()
+        .await?;
+
+    // A response that failed to render sends no callbacks
+    let res = client
+        .post("http://localhost:3035/broken")
+        .json(&json!({ "id": "p-0" }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 500);
+
+    let mut primary_logs = client
+        .get("http://localhost:3035/_admin/logs/stream")
+        .send()
+        .await?;
+    let (mut receiver_buffer, mut primary_buffer) = (String::new(), String::new());
+
+    // The response does not wait for the callback
+    let start = std::time::Instant::now();
+    let res = client
+        .post("http://localhost:3035/payments")
+        .json(&json!({ "id": "p-1", "amount": 42 }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 202);
+    assert!(start.elapsed() < Duration::from_millis(300));
+
+    for _ in 0..3 {
+        let entry = next_log(&mut receiver_logs, &mut receiver_buffer).await;
+        assert_eq!(entry["path"], "/hooks/payment");
+        assert_eq!(
+            entry["body"],
+            json!({ "id": "p-1", "status": "paid", "amount": 42 })
+        );
+    }
+    assert!(start.elapsed() >= Duration::from_millis(300));
+
+    let request = next_log(&mut primary_logs, &mut primary_buffer).await;
+    assert_eq!(request["path"], "/payments");
+    let outcome = next_log(&mut primary_logs, &mut primary_buffer).await;
+    assert_eq!(outcome["method"], "POST");
+    assert_eq!(
+        outcome["callback"]["url"],
+        "http://localhost:3036/hooks/payment"
+    );
+    assert_eq!(outcome["callback"]["status"], 200);
+    assert_eq!(outcome["callback"]["attempts"], 3);
+    assert_eq!(outcome["error"], Value::Null);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_record_mode() -> Result<(), Box<dyn std::error::Error>> {
+    let _upstream = TestServer::start(Some(3037), Some("expectations_record_upstream.json"));
+    wait_for_server("http://localhost:3037").await;
+    let _recorder = TestServer::start(Some(3038), Some("expectations_record_local.json"));
+    wait_for_server("http://localhost:3038").await;
+    let _global = TestServer::start_with_args(
+        Some(3039),
+        Some("expectations_record_global.json"),
+        &["--record"],
+    );
+    wait_for_server("http://localhost:3039").await;
+
+    let client = reqwest::Client::new();
+    for mock in [
+        json!({
+            "id": 1,
+            "condition": { "method": "GET", "path": "/users/1" },
+            "response": { "status_code": 200, "headers": { "X-Upstream": "yes" }, "body": { "id": 1, "name": "Ada" } }
+        }),
+        json!({
+            "id": 2,
+            "condition": { "method": "POST", "path": "/search" },
+            "response": { "status_code": 201, "body_type": "text", "body": "found" }
+        }),
+    ] {
+        client
+            .post("http://localhost:3037/_admin/mocks")
+            .json(&mock)
+            .send()
+            .await?;
+    }
+
+    // Per-proxy recording with extra match keys
+    client
+        .post("http://localhost:3038/_admin/mocks")
+        .json(&json!({
+            "id": 1,
+            "condition": { "path": "/*" },
+            "response": {
+                "proxy": {
+                    "url": "http://localhost:3037",
+                    "record": { "query": true, "body": true, "headers": ["X-Tenant"] }
+                }
+            }
+        }))
+        .send()
+        .await?;
+
+    for _ in 0..2 {
+        let res = client
+            .get("http://localhost:3038/users/1?expand=true")
+            .header("X-Tenant", "acme")
+            .send()
+            .await?;
+        assert_eq!(res.status(), 200);
+    }
+    let res = client
+        .post("http://localhost:3038/search")
+        .json(&json!({ "q": "ada" }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 201);
+
+    // Duplicates are skipped and recordings are persisted
+    let mocks: Vec<Value> = client
+        .get("http://localhost:3038/_admin/mocks")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(mocks.len(), 3);
+    let saved: Vec<Value> =
+        serde_json::from_str(&std::fs::read_to_string("expectations_record_local.json")?)?;
+    assert_eq!(saved.len(), 3);
+
+    let user = &mocks[1];
+    assert_eq!(user["condition"]["method"], "GET");
+    assert_eq!(user["condition"]["path"], "/users/1");
+    assert_eq!(user["condition"]["query"], json!({ "expand": "true" }));
+    assert_eq!(user["condition"]["headers"], json!({ "x-tenant": "acme" }));
+    assert_eq!(user["response"]["status_code"], 200);
+    assert_eq!(user["response"]["headers"]["x-upstream"], "yes");
+    assert_eq!(user["response"]["body"], json!({ "id": 1, "name": "Ada" }));
+
+    let search = &mocks[2];
+    assert_eq!(search["condition"]["body"], json!({ "q": "ada" }));
+    assert_eq!(search["response"]["status_code"], 201);
+    assert_eq!(search["response"]["body_type"], "text");
+    assert_eq!(search["response"]["body"], "found");
+
+    // Without the proxy the recordings replay the upstream responses
+    client
+        .delete("http://localhost:3038/_admin/mocks/1")
+        .send()
+        .await?;
+    let res = client
+        .get("http://localhost:3038/users/1?expand=true")
+        .header("X-Tenant", "acme")
+        .send()
+        .await?;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["x-upstream"], "yes");
+    assert_eq!(
+        res.json::<Value>().await?,
+        json!({ "id": 1, "name": "Ada" })
+    );
+    let res = client
+        .get("http://localhost:3038/users/1?expand=true")
+        .send()
+        .await?;
+    assert_eq!(res.status(), 404);
+
+    // --record records every proxy on method and path
+    client
+        .post("http://localhost:3039/_admin/mocks")
+        .json(&json!({
+            "id": 1,
+            "condition": { "path": "/users/*" },
+            "response": { "proxy": { "url": "http://localhost:3037" } }
+        }))
+        .send()
+        .await?;
+    client
+        .get("http://localhost:3039/users/1?expand=true")
+        .send()
+        .await?;
+    let mocks: Vec<Value> = client
+        .get("http://localhost:3039/_admin/mocks")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(mocks.len(), 2);
+    assert_eq!(mocks[1]["condition"]["path"], "/users/1");
+    assert_eq!(mocks[1]["condition"]["query"], Value::Null);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_har_import_and_journal() -> Result<(), Box<dyn std::error::Error>> {
+    let _server = TestServer::start(Some(3040), Some("expectations_har.json"));
+    wait_for_server("http://localhost:3040").await;
+
+    let users = json!({
+        "request": {
+            "method": "GET",
+            "url": "https://api.example.com/api/users?page=2",
+            "headers": [
+                { "name": ":authority", "value": "api.example.com" },
+                { "name": "X-Tenant", "value": "acme" }
+            ]
+        },
+        "response": {
+            "status": 200,
+            "headers": [
+                { "name": "Content-Type", "value": "application/json" },
+                { "name": "Content-Encoding", "value": "gzip" }
+            ],
+            "content": { "mimeType": "application/json", "text": "{\"users\":[\"ada\"]}" }
+        }
+    });
+    let archive = json!({
+        "log": {
+            "version": "1.2",
+            "creator": { "name": "devtools", "version": "1" },
+            "entries": [
+                users,
+                {
+                    "request": {
+                        "method": "POST",
+                        "url": "https://api.example.com/api/login",
+                        "postData": { "mimeType": "application/json", "text": "{\"user\":\"ada\"}" }
+                    },
+                    "response": {
+                        "status": 201,
+                        "content": { "mimeType": "text/plain", "text": "b2s=", "encoding": "base64" }
+                    }
+                },
+                {
+                    "request": { "method": "GET", "url": "https://api.example.com/aborted" },
+                    "response": { "status": 0, "content": {} }
+                },
+                users
+            ]
+        }
+    });
+
+    let client = reqwest::Client::new();
+    let res = client
+        .post("http://localhost:3040/_admin/import/har?match=bogus")
+        .json(&archive)
+        .send()
+        .await?;
+    assert_eq!(res.status(), 400);
+
+    let res = client
+        .post("http://localhost:3040/_admin/import/har?match=query,header:x-tenant")
+        .json(&archive)
+        .send()
+        .await?;
+    assert_eq!(res.status(), 201);
+    assert_eq!(
+        res.json::<Value>().await?,
+        json!({ "imported": 2, "skipped": 2 })
+    );
+
+    let mocks: Vec<Value> = client
+        .get("http://localhost:3040/_admin/mocks")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(mocks.len(), 2);
+    assert_eq!(mocks[0]["condition"]["query"], json!({ "page": "2" }));
+    assert_eq!(
+        mocks[0]["condition"]["headers"],
+        json!({ "x-tenant": "acme" })
+    );
+    assert_eq!(mocks[1]["condition"]["body"], Value::Null);
+
+    let res = client
+        .get("http://localhost:3040/api/users?page=2")
+        .header("X-Tenant", "acme")
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .await?;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["content-encoding"], "gzip");
+    let res = client
+        .get("http://localhost:3040/api/users")
+        .header("X-Tenant", "acme")
+        .send()
+        .await?;
+    assert_eq!(res.status(), 404);
+    let res = client
+        .post("http://localhost:3040/api/login")
+        .body("anything")
+        .send()
+        .await?;
+    assert_eq!(res.status(), 201);
+    assert_eq!(res.text().await?, "ok");
+
+    // The journal holds the served traffic with decoded bodies
+    let journal: Value = client
+        .get("http://localhost:3040/_admin/journal.har")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(journal["log"]["version"], "1.2");
+    assert_eq!(journal["log"]["creator"]["name"], "mimicrab");
+    let entries = journal["log"]["entries"].as_array().unwrap();
+    assert_eq!(entries.len(), 3);
+
+    let first = &entries[0];
+    assert_eq!(first["request"]["method"], "GET");
+    assert_eq!(
+        first["request"]["url"],
+        "http://localhost:3040/api/users?page=2"
+    );
+    assert_eq!(
+        first["request"]["queryString"],
+        json!([{ "name": "page", "value": "2" }])
+    );
+    assert_eq!(first["response"]["status"], 200);
+    assert_eq!(first["response"]["content"]["mimeType"], "application/json");
+    let text = first["response"]["content"]["text"].as_str().unwrap();
+    assert_eq!(
+        serde_json::from_str::<Value>(text)?,
+        json!({ "users": ["ada"] })
+    );
+    assert!(first["startedDateTime"].is_string());
+
+    assert_eq!(entries[1]["response"]["status"], 404);
+    assert_eq!(entries[2]["request"]["postData"]["text"], "anything");
+    assert_eq!(entries[2]["response"]["content"]["text"], "ok");
+
+    // An exported journal can be imported again. Without the query both
+    // GET requests match the same condition, and the login is already there.
+    let res = client
+        .post("http://localhost:3040/_admin/import/har")
+        .json(&journal)
+        .send()
+        .await?;
+    assert_eq!(
+        res.json::<Value>().await?,
+        json!({ "imported": 1, "skipped": 2 })
+    );
+
+    // Binary request bodies are journaled as base64, large ones are capped
+    client
+        .post("http://localhost:3040/api/login")
+        .body(vec![0xff, 0x00, 0x81])
+        .send()
+        .await?;
+    client
+        .post("http://localhost:3040/api/login")
+        .body("a".repeat(2 * 1024 * 1024))
+        .send()
+        .await?;
+    let journal: Value = client
+        .get("http://localhost:3040/_admin/journal.har")
+        .send()
+        .await?
+        .json()
+        .await?;
+    let entries = journal["log"]["entries"].as_array().unwrap();
+    assert_eq!(entries.len(), 5);
+    let binary = &entries[3]["request"];
+    assert_eq!(binary["postData"]["text"], "/wCB");
+    assert_eq!(binary["postData"]["encoding"], "base64");
+    assert_eq!(binary["bodySize"], 3);
+    let large = &entries[4]["request"];
+    assert_eq!(
+        large["postData"]["text"].as_str().unwrap().len(),
+        1024 * 1024
+    );
+    assert!(large["postData"]["encoding"].is_null());
+    assert_eq!(large["bodySize"], 2 * 1024 * 1024);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_proxy_rewriting() -> Result<(), Box<dyn std::error::Error>> {
+    let _upstream = TestServer::start(Some(3041), Some("expectations_rewrite_upstream.json"));
+    wait_for_server("http://localhost:3041").await;
+    let _server = TestServer::start(Some(3042), Some("expectations_rewrite.json"));
+    wait_for_server("http://localhost:3042").await;
+
+    let client = reqwest::Client::new();
+    client
+        .post("http://localhost:3041/_admin/mocks")
+        .json(&json!({
+            "condition": { "path": "/*" },
+            "response": {
+                "status_code": 200,
+                "body": { "path": "{{path}}", "host": "{{headers.host}}", "page": "{{query.page}}" }
+            }
+        }))
+        .send()
+        .await?;
+
+    for mock in [
+        json!({
+            "condition": { "path": "/api/payments/*" },
+            "response": { "proxy": {
+                "url": "http://localhost:3041/v2",
+                "strip_prefix": "/api/payments",
+                "host": "upstream"
+            } }
+        }),
+        json!({
+            "condition": { "path": "/legacy/*" },
+            "response": { "proxy": {
+                "url": "http://localhost:3041",
+                "rewrite": [
+                    { "pattern": "^/legacy/users/(\\d+)$", "replacement": "/users/$1/profile" },
+                    { "pattern": "^/legacy", "replacement": "/old" }
+                ]
+            } }
+        }),
+        json!({
+            "condition": { "path": "/tenants/:id/*" },
+            "response": { "proxy": {
+                "url": "http://localhost:3041/t/{{path[1]}}/info",
+                "append_path": false
+            } }
+        }),
+    ] {
+        let res = client
+            .post("http://localhost:3042/_admin/mocks")
+            .json(&mock)
+            .send()
+            .await?;
+        assert_eq!(res.status(), 201);
+    }
+
+    let res = client
+        .post("http://localhost:3042/_admin/mocks")
+        .json(&json!({
+            "condition": { "path": "/broken" },
+            "response": { "proxy": {
+                "url": "http://localhost:3041",
+                "rewrite": [{ "pattern": "(", "replacement": "" }]
+            } }
+        }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 400);
+
+    let body: Value = client
+        .get("http://localhost:3042/api/payments/charges?page=3")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(
+        body,
+        json!({ "path": "/v2/charges", "host": "localhost:3041", "page": 3 })
+    );
+
+    let body: Value = client
+        .get("http://localhost:3042/legacy/users/42")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(body["path"], "/users/42/profile");
+    assert_eq!(body["host"], "localhost:3042");
+    let body: Value = client
+        .get("http://localhost:3042/legacy/orders")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(body["path"], "/old/orders");
+
+    let body: Value = client
+        .get("http://localhost:3042/tenants/acme/anything?page=1")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(body["path"], "/t/acme/info");
+    assert_eq!(body["page"], Value::Null);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_proxy_transforms() -> Result<(), Box<dyn std::error::Error>> {
+    let _upstream = TestServer::start(Some(3043), Some("expectations_transform_upstream.json"));
+    wait_for_server("http://localhost:3043").await;
+    let _server = TestServer::start(Some(3044), Some("expectations_transform.json"));
+    wait_for_server("http://localhost:3044").await;
+
+    let client = reqwest::Client::new();
+    for mock in [
+        json!({
+            "condition": { "method": "GET", "path": "/orders/1" },
+            "response": {
+                "status_code": 200,
+                "headers": { "X-Internal": "secret", "X-Keep": "yes" },
+                "body": { "id": 1, "status": "open", "internal": { "cost": 3 }, "items": [1, 2] }
+            }
+        }),
+        json!({
+            "condition": { "path": "/echo" },
+            "response": { "script": "return { status = 200, headers = { [\"Content-Type\"] = \"application/json\" }, body = { received = request.body, added = request.headers[\"x-added\"], secret = request.headers[\"x-secret\"] } }" }
+        }),
+    ] {
+        client
+            .post("http://localhost:3043/_admin/mocks")
+            .json(&mock)
+            .send()
+            .await?;
+    }
+
+    for mock in [
+        json!({
+            "condition": { "path": "/orders/*" },
+            "response": { "proxy": {
+                "url": "http://localhost:3043",
+                "transform": { "response": {
+                    "status_code": 203,
+                    "remove_headers": ["X-Internal"],
+                    "headers": { "X-Proxied": "mimicrab" },
+                    "json_patch": [
+                        { "op": "remove", "path": "/internal" },
+                        { "op": "add", "path": "/items/-", "value": 3 }
+                    ],
+                    "merge_patch": { "status": "closed" }
+                } }
+            } }
+        }),
+        json!({
+            "condition": { "path": "/echo" },
+            "response": { "proxy": {
+                "url": "http://localhost:3043",
+                "transform": {
+                    "request": {
+                        "remove_headers": ["X-Secret"],
+                        "headers": { "X-Added": "1" },
+                        "merge_patch": { "injected": true }
+                    },
+                    "response": {
+                        "script": "message.body.wrapped = true\nmessage.status = 299\nmessage.headers[\"x-method\"] = request.method\nreturn message"
+                    }
+                }
+            } }
+        }),
+        json!({
+            "condition": { "path": "/stamp" },
+            "response": { "proxy": {
+                "url": "http://localhost:3043",
+                "rewrite": [{ "pattern": "^/stamp", "replacement": "/echo" }],
+                "transform": { "response": {
+                    "script": "message.body.seen = request.body.name\nreturn message"
+                } }
+            } }
+        }),
+        json!({
+            "condition": { "path": "/failing/*" },
+            "response": { "proxy": {
+                "url": "http://localhost:3043",
+                "rewrite": [{ "pattern": "^/failing", "replacement": "/orders" }],
+                "transform": { "response": {
+                    "json_patch": [{ "op": "test", "path": "/id", "value": 2 }]
+                } }
+            } }
+        }),
+    ] {
+        let res = client
+            .post("http://localhost:3044/_admin/mocks")
+            .json(&mock)
+            .send()
+            .await?;
+        assert_eq!(res.status(), 201);
+    }
+
+    // Compressed upstream bodies are decoded before patching
+    let res = client
+        .get("http://localhost:3044/orders/1")
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .await?;
+    assert_eq!(res.status(), 203);
+    assert!(res.headers().get("x-internal").is_none());
+    assert!(res.headers().get("content-encoding").is_none());
+    assert_eq!(res.headers()["x-keep"], "yes");
+    assert_eq!(res.headers()["x-proxied"], "mimicrab");
+    assert_eq!(
+        res.json::<Value>().await?,
+        json!({ "id": 1, "status": "closed", "items": [1, 2, 3] })
+    );
+
+    let res = client
+        .post("http://localhost:3044/echo")
+        .header("X-Secret", "hidden")
+        .json(&json!({ "name": "ada" }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 299);
+    assert_eq!(res.headers()["x-method"], "POST");
+    assert_eq!(
+        res.json::<Value>().await?,
+        json!({
+            "received": { "name": "ada", "injected": true },
+            "added": "1",
+            "wrapped": true
+        })
+    );
+
+    // A response script still sees the body of the request it answers
+    let res = client
+        .post("http://localhost:3044/stamp")
+        .json(&json!({ "name": "ada" }))
+        .send()
+        .await?;
+    assert_eq!(
+        res.json::<Value>().await?,
+        json!({ "received": { "name": "ada" }, "seen": "ada" })
+    );
+
+    let res = client.get("http://localhost:3044/failing/1").send().await?;
+    assert_eq!(res.status(), 500);
+    assert!(res.text().await?.starts_with("Transform error"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_proxy_chaos() -> Result<(), Box<dyn std::error::Error>> {
+    let _upstream = TestServer::start(Some(3045), Some("expectations_chaos_upstream.json"));
+    wait_for_server("http://localhost:3045").await;
+    let _server = TestServer::start(Some(3046), Some("expectations_chaos.json"));
+    wait_for_server("http://localhost:3046").await;
+
+    let client = reqwest::Client::new();
+    client
+        .post("http://localhost:3045/_admin/mocks")
+        .json(&json!({
+            "condition": { "path": "/*" },
+            "response": { "status_code": 200, "body": { "ok": true } }
+        }))
+        .send()
+        .await?;
+
+    let proxy = json!({ "url": "http://localhost:3045" });
+    for (path, response) in [
+        ("/before", json!({ "latency": 400, "proxy": proxy })),
+        (
+            "/after",
+            json!({ "latency": 400, "proxy": { "url": "http://localhost:3045", "latency_phase": "after" } }),
+        ),
+        (
+            "/jitter",
+            json!({ "proxy": proxy, "jitter": { "probability": 1.0, "status_code": 503, "body": { "injected": true } } }),
+        ),
+        (
+            "/reset",
+            json!({ "proxy": proxy, "fault": "connection_reset" }),
+        ),
+        (
+            "/truncated",
+            json!({ "proxy": proxy, "fault": "truncated_body" }),
+        ),
+    ] {
+        let res = client
+            .post("http://localhost:3046/_admin/mocks")
+            .json(&json!({ "condition": { "path": path }, "response": response }))
+            .send()
+            .await?;
+        assert_eq!(res.status(), 201);
+    }
+
+    // Returns how long after `sent` the upstream saw the last request
+    let upstream_delay = |sent: chrono::DateTime<chrono::Utc>| {
+        let client = client.clone();
+        async move {
+            let journal: Value = client
+                .get("http://localhost:3045/_admin/journal.har")
+                .send()
+                .await?
+                .json()
+                .await?;
+            let entries = journal["log"]["entries"].as_array().unwrap().clone();
+            let started = entries.last().unwrap()["startedDateTime"]
+                .as_str()
+                .unwrap()
+                .parse::<chrono::DateTime<chrono::Utc>>()?;
+            Ok::<_, Box<dyn std::error::Error>>((
+                entries.len(),
+                (started - sent).num_milliseconds(),
+            ))
+        }
+    };
+
+    let sent = chrono::Utc::now();
+    let start = std::time::Instant::now();
+    let res = client.get("http://localhost:3046/before").send().await?;
+    assert_eq!(res.status(), 200);
+    assert!(start.elapsed() >= Duration::from_millis(400));
+    let (count, delay) = upstream_delay(sent).await?;
+    assert_eq!(count, 1);
+    assert!(delay >= 350, "upstream called after {}ms", delay);
+
+    let sent = chrono::Utc::now();
+    let start = std::time::Instant::now();
+    let res = client.get("http://localhost:3046/after").send().await?;
+    assert_eq!(res.status(), 200);
+    assert!(start.elapsed() >= Duration::from_millis(400));
+    let (count, delay) = upstream_delay(sent).await?;
+    assert_eq!(count, 2);
+    assert!(delay < 350, "upstream called after {}ms", delay);
+
+    // Injected errors do not reach the upstream
+    let res = client.get("http://localhost:3046/jitter").send().await?;
+    assert_eq!(res.status(), 503);
+    assert_eq!(res.json::<Value>().await?, json!({ "injected": true }));
+    assert!(
+        client
+            .get("http://localhost:3046/reset")
+            .send()
+            .await
+            .is_err()
+    );
+    assert_eq!(upstream_delay(sent).await?.0, 2);
+
+    // Faults that corrupt a response corrupt the upstream one
+    let result = match client.get("http://localhost:3046/truncated").send().await {
+        Ok(res) => res.bytes().await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    assert!(result.is_err());
+    assert_eq!(upstream_delay(sent).await?.0, 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fallback_proxy() -> Result<(), Box<dyn std::error::Error>> {
+    let _upstream = TestServer::start(Some(3047), Some("expectations_fallback_upstream.json"));
+    wait_for_server("http://localhost:3047").await;
+    let _server = TestServer::start_with_args(
+        Some(3048),
+        Some("expectations_fallback.json"),
+        &["--fallback-proxy", "http://localhost:3047"],
+    );
+    wait_for_server("http://localhost:3048").await;
+
+    let client = reqwest::Client::new();
+    client
+        .post("http://localhost:3047/_admin/mocks")
+        .json(&json!({
+            "condition": { "path": "/*" },
+            "response": { "status_code": 200, "body": { "source": "upstream", "path": "{{path}}" } }
+        }))
+        .send()
+        .await?;
+    client
+        .post("http://localhost:3048/_admin/mocks")
+        .json(&json!({
+            "condition": { "path": "/users/1" },
+            "response": { "status_code": 200, "body": { "source": "mock" } }
+        }))
+        .send()
+        .await?;
+
+    let mut logs = client
+        .get("http://localhost:3048/_admin/logs/stream")
+        .send()
+        .await?;
+    let mut buffer = String::new();
+
+    let body: Value = client
+        .get("http://localhost:3048/users/1")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(body["source"], "mock");
+    let entry = next_log(&mut logs, &mut buffer).await;
+    assert_eq!(entry["matched"], true);
+    assert_eq!(entry["passthrough"], false);
+
+    let body: Value = client
+        .get("http://localhost:3048/users/2")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(body["source"], "upstream");
+    let entry = next_log(&mut logs, &mut buffer).await;
+    assert_eq!(entry["matched"], false);
+    assert_eq!(entry["passthrough"], true);
+
+    let metrics = client
+        .get("http://localhost:3048/_admin/metrics")
+        .send()
+        .await?
+        .text()
+        .await?;
+    assert!(
+        metrics.contains(r#"mimicrab_requests_total{matched="passthrough",path="/users/2"} 1"#)
+    );
+    assert!(metrics.contains(r#"mimicrab_requests_total{matched="true",path="/users/1"} 1"#));
+
+    let fallback: Value = client
+        .get("http://localhost:3048/_admin/fallback")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(fallback["url"], "http://localhost:3047");
+
+    // The fallback can be cleared and replaced at runtime
+    let res = client
+        .delete("http://localhost:3048/_admin/fallback")
+        .send()
+        .await?;
+    assert_eq!(res.status(), 204);
+    let res = client.get("http://localhost:3048/users/2").send().await?;
+    assert_eq!(res.status(), 404);
+
+    let res = client
+        .put("http://localhost:3048/_admin/fallback")
+        .json(&json!({ "url": "http://localhost:3047", "rewrite": [{ "pattern": "(", "replacement": "" }] }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 400);
+    let res = client
+        .put("http://localhost:3048/_admin/fallback")
+        .json(&json!({ "url": "http://localhost:3047", "strip_prefix": "/api" }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 200);
+    let body: Value = client
+        .get("http://localhost:3048/api/orders")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(body["path"], "/orders");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_proxy_timeouts_and_retries() -> Result<(), Box<dyn std::error::Error>> {
+    let _upstream = TestServer::start(Some(3049), Some("expectations_retry_upstream.json"));
+    wait_for_server("http://localhost:3049").await;
+    let _server = TestServer::start(Some(3050), Some("expectations_retry.json"));
+    wait_for_server("http://localhost:3050").await;
+
+    let client = reqwest::Client::new();
+    for mock in [
+        json!({
+            "condition": { "path": "/slow" },
+            "response": { "status_code": 200, "latency": 1000, "body": { "ok": true } }
+        }),
+        json!({
+            "condition": { "path": "/flaky" },
+            "response": { "script": "if request.counter < 3 then return { status = 503 } end\nreturn { status = 200, body = { attempt = request.counter } }" }
+        }),
+    ] {
+        client
+            .post("http://localhost:3049/_admin/mocks")
+            .json(&mock)
+            .send()
+            .await?;
+    }
+
+    for mock in [
+        json!({
+            "condition": { "path": "/slow" },
+            "response": { "proxy": {
+                "url": "http://localhost:3049",
+                "timeouts": { "total_ms": 200 }
+            } }
+        }),
+        json!({
+            "condition": { "path": "/slow-mapped" },
+            "response": { "proxy": {
+                "url": "http://localhost:3049/slow",
+                "append_path": false,
+                "timeouts": { "connect_ms": 500, "read_ms": 200 },
+                "errors": { "timeout": { "status_code": 503, "body": { "error": "upstream slow" } } }
+            } }
+        }),
+        json!({
+            "condition": { "path": "/refused" },
+            "response": { "proxy": {
+                "url": "http://localhost:3099",
+                "errors": { "refused": { "status_code": 503, "headers": { "X-Failure": "refused" } } }
+            } }
+        }),
+        json!({
+            "condition": { "path": "/dns" },
+            "response": { "proxy": { "url": "http://mimicrab-upstream.invalid" } }
+        }),
+        json!({
+            "condition": { "path": "/flaky" },
+            "response": { "proxy": {
+                "url": "http://localhost:3049",
+                "retry": { "max_retries": 3, "backoff_ms": 50 }
+            } }
+        }),
+    ] {
+        let res = client
+            .post("http://localhost:3050/_admin/mocks")
+            .json(&mock)
+            .send()
+            .await?;
+        assert_eq!(res.status(), 201);
+    }
+
+    let start = std::time::Instant::now();
+    let res = client.get("http://localhost:3050/slow").send().await?;
+    assert_eq!(res.status(), 504);
+    assert!(start.elapsed() < Duration::from_millis(900));
+    assert!(res.text().await?.starts_with("Proxy request failed"));
+
+    let res = client
+        .get("http://localhost:3050/slow-mapped")
+        .send()
+        .await?;
+    assert_eq!(res.status(), 503);
+    assert_eq!(
+        res.json::<Value>().await?,
+        json!({ "error": "upstream slow" })
+    );
+
+    let res = client.get("http://localhost:3050/refused").send().await?;
+    assert_eq!(res.status(), 503);
+    assert_eq!(res.headers()["x-failure"], "refused");
+
+    let res = client.get("http://localhost:3050/dns").send().await?;
+    assert_eq!(res.status(), 502);
+
+    // Only idempotent requests are retried
+    let res = client.post("http://localhost:3050/flaky").send().await?;
+    assert_eq!(res.status(), 503);
+    let res = client.get("http://localhost:3050/flaky").send().await?;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.json::<Value>().await?, json!({ "attempt": 3 }));
+
+    let metrics = client
+        .get("http://localhost:3050/_admin/metrics")
+        .send()
+        .await?
+        .text()
+        .await?;
+    assert!(metrics.contains(r#"mimicrab_upstream_failures_total{kind="timeout"} 2"#));
+    assert!(metrics.contains(r#"mimicrab_upstream_failures_total{kind="refused"} 1"#));
+    assert!(metrics.contains(r#"mimicrab_upstream_failures_total{kind="dns"} 1"#));
+    assert!(metrics.contains(r#"mimicrab_upstream_retries_total{kind="status"} 1"#));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_proxy_streaming() -> Result<(), Box<dyn std::error::Error>> {
+    let _upstream = TestServer::start(Some(3051), Some("expectations_stream_upstream.json"));
+    wait_for_server("http://localhost:3051").await;
+    let _server = TestServer::start(Some(3052), Some("expectations_stream.json"));
+    wait_for_server("http://localhost:3052").await;
+
+    let client = reqwest::Client::new();
+    client
+        .post("http://localhost:3052/_admin/mocks")
+        .json(&json!({
+            "condition": { "path": "/events" },
+            "response": { "proxy": {
+                "url": "http://localhost:3051/_admin/logs/stream",
+                "append_path": false
+            } }
+        }))
+        .send()
+        .await?;
+
+    // The upstream log stream never ends, so events only arrive through
+    // the proxy if it passes them on as they come
+    let mut events = client.get("http://localhost:3052/events").send().await?;
+    assert_eq!(events.status(), 200);
+    let mut buffer = String::new();
+    client.get("http://localhost:3051/ping").send().await?;
+    let entry = next_log(&mut events, &mut buffer).await;
+    assert_eq!(entry["path"], "/ping");
+    client.get("http://localhost:3051/pong").send().await?;
+    let entry = next_log(&mut events, &mut buffer).await;
+    assert_eq!(entry["path"], "/pong");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_proxy_upgrade() -> Result<(), Box<dyn std::error::Error>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn read_head(stream: &mut tokio::net::TcpStream) -> String {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8];
+            stream.read_exact(&mut byte).await.unwrap();
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap().to_lowercase()
+    }
+
+    // Accepts one upgrade, then echoes whatever it receives
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:3053").await?;
+    let upstream = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let head = read_head(&mut stream).await;
+        stream
+            .write_all(
+                b"HTTP/1.1 101 Switching Protocols\r\n\
+                  Connection: Upgrade, X-Hop\r\n\
+                  Upgrade: websocket\r\n\
+                  X-Hop: 1\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut buf = [0u8; 64];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            stream.write_all(&buf[..n]).await.unwrap();
+        }
+        head
+    });
+
+    let _server = TestServer::start(Some(3054), Some("expectations_upgrade.json"));
+    wait_for_server("http://localhost:3054").await;
+    reqwest::Client::new()
+        .post("http://localhost:3054/_admin/mocks")
+        .json(&json!({
+            "condition": { "path": "/ws" },
+            "response": { "proxy": { "url": "http://127.0.0.1:3053" } }
+        }))
+        .send()
+        .await?;
+
+    let mut client = tokio::net::TcpStream::connect("127.0.0.1:3054").await?;
+    client
+        .write_all(
+            b"GET /ws HTTP/1.1\r\n\
+              Host: localhost\r\n\
+              Connection: Upgrade, X-Hop\r\n\
+              Upgrade: websocket\r\n\
+              X-Hop: 1\r\n\
+              Keep-Alive: timeout=5\r\n\r\n",
+        )
+        .await?;
+    let head = read_head(&mut client).await;
+    assert!(head.starts_with("http/1.1 101"));
+    assert!(head.contains("upgrade: websocket"));
+    assert!(!head.contains("x-hop"));
+
+    for message in [&b"ping"[..], &b"hello upstream"[..]] {
+        client.write_all(message).await?;
+        let mut echoed = vec![0u8; message.len()];
+        tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut echoed)).await??;
+        assert_eq!(echoed, message);
+    }
+    drop(client);
+
+    // Hop-by-hop headers stay on their hop, the upgrade goes through
+    let forwarded = tokio::time::timeout(Duration::from_secs(5), upstream).await??;
+    assert!(forwarded.starts_with("get /ws http/1.1"));
+    assert!(forwarded.contains("upgrade: websocket"));
+    assert!(!forwarded.contains("x-hop"));
+    assert!(!forwarded.contains("keep-alive"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_proxy_load_balancing() -> Result<(), Box<dyn std::error::Error>> {
+    async fn targets(client: &reqwest::Client, path: &str, count: usize) -> Vec<String> {
+        let mut targets = Vec::new();
+        for _ in 0..count {
+            let res = client
+                .get(format!("http://localhost:3057{}", path))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), 200);
+            let body: Value = res.json().await.unwrap();
+            targets.push(body["target"].as_str().unwrap().to_string());
+        }
+        targets
+    }
+
+    let _a = TestServer::start(Some(3055), Some("expectations_balance_a.json"));
+    let _b = TestServer::start(Some(3056), Some("expectations_balance_b.json"));
+    wait_for_server("http://localhost:3055").await;
+    wait_for_server("http://localhost:3056").await;
+    let _server = TestServer::start(Some(3057), Some("expectations_balance.json"));
+    wait_for_server("http://localhost:3057").await;
+
+    let client = reqwest::Client::new();
+    for (port, name) in [(3055, "a"), (3056, "b")] {
+        client
+            .post(format!("http://localhost:{}/_admin/mocks", port))
+            .json(&json!({
+                "condition": { "path": "/*" },
+                "response": { "status_code": 200, "body": { "target": name } }
+            }))
+            .send()
+            .await?;
+    }
+    for mock in [
+        json!({
+            "condition": { "path": "/rr" },
+            "response": { "proxy": { "upstreams": [
+                { "url": "http://localhost:3055" },
+                { "url": "http://localhost:3056" }
+            ] } }
+        }),
+        json!({
+            "condition": { "path": "/weighted" },
+            "response": { "proxy": {
+                "upstreams": [
+                    { "url": "http://localhost:3055", "weight": 3 },
+                    { "url": "http://localhost:3056" }
+                ],
+                "balance": "weighted"
+            } }
+        }),
+        json!({
+            "condition": { "path": "/random" },
+            "response": { "proxy": {
+                "upstreams": [
+                    { "url": "http://localhost:3055" },
+                    { "url": "http://localhost:3056" }
+                ],
+                "balance": "random"
+            } }
+        }),
+        json!({
+            "id": 99,
+            "condition": { "path": "/shadowed" },
+            "response": { "status_code": 200, "body": { "target": "mock" }, "shadow": {
+                "upstream": { "upstreams": [
+                    { "url": "http://localhost:3055" },
+                    { "url": "http://localhost:3056" }
+                ] }
+            } }
+        }),
+        json!({
+            "condition": { "path": "/failover" },
+            "response": { "proxy": {
+                "upstreams": [
+                    { "url": "http://127.0.0.1:3099" },
+                    { "url": "http://localhost:3055" }
+                ],
+                "ejection": { "consecutive_failures": 2 }
+            } }
+        }),
+    ] {
+        let res = client
+            .post("http://localhost:3057/_admin/mocks")
+            .json(&mock)
+            .send()
+            .await?;
+        assert_eq!(res.status(), 201);
+    }
+    let res = client
+        .post("http://localhost:3057/_admin/mocks")
+        .json(&json!({ "condition": { "path": "/none" }, "response": { "proxy": {} } }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 400);
+
+    // Shadow calls leave the rotation of live traffic alone
+    assert_eq!(targets(&client, "/shadowed", 1).await, ["mock"]);
+    for _ in 0..50 {
+        let metrics = client
+            .get("http://localhost:3057/_admin/metrics")
+            .send()
+            .await?
+            .text()
+            .await?;
+        if metrics.contains(r#"mimicrab_shadow_comparisons_total{expectation="99""#) {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    assert_eq!(targets(&client, "/rr", 4).await, ["a", "b", "a", "b"]);
+    assert_eq!(targets(&client, "/weighted", 4).await, ["a", "a", "a", "b"]);
+    let random = targets(&client, "/random", 40).await;
+    assert!(random.contains(&"a".to_string()) && random.contains(&"b".to_string()));
+
+    // The refused target is failed over twice, then ejected
+    assert!(
+        targets(&client, "/failover", 8)
+            .await
+            .iter()
+            .all(|t| t == "a")
+    );
+    let metrics = client
+        .get("http://localhost:3057/_admin/metrics")
+        .send()
+        .await?
+        .text()
+        .await?;
+    assert!(metrics.contains(r#"mimicrab_upstream_failures_total{kind="refused"} 2"#));
+    assert!(
+        metrics
+            .contains(r#"mimicrab_upstream_ejections_total{upstream="http://127.0.0.1:3099"} 1"#)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_proxy_zero_weights_from_file() -> Result<(), Box<dyn std::error::Error>> {
+    let _upstream = TestServer::start(Some(3063), Some("expectations_zero_weight_upstream.json"));
+    wait_for_server("http://localhost:3063").await;
+    let client = reqwest::Client::new();
+    client
+        .post("http://localhost:3063/_admin/mocks")
+        .json(&json!({
+            "condition": { "path": "/*" },
+            "response": { "status_code": 200, "body": { "target": "upstream" } }
+        }))
+        .send()
+        .await?;
+
+    // Files are not validated like the admin API, so all-zero weights get in
+    let path = "expectations_zero_weight.json";
+    let mocks: Vec<Value> = ["weighted", "random"]
+        .iter()
+        .enumerate()
+        .map(|(i, balance)| {
+            json!({
+                "id": i + 1,
+                "condition": { "path": format!("/{}", balance) },
+                "response": { "proxy": {
+                    "upstreams": [{ "url": "http://localhost:3063", "weight": 0 }],
+                    "balance": balance
+                } }
+            })
+        })
+        .collect();
+    std::fs::write(path, serde_json::to_string(&mocks)?)?;
+    let _server = TestServer::start(Some(3064), Some(path));
+    wait_for_server("http://localhost:3064").await;
+
+    for balance in ["weighted", "random"] {
+        for _ in 0..2 {
+            let res = client
+                .get(format!("http://localhost:3064/{}", balance))
+                .send()
+                .await?;
+            assert_eq!(res.status(), 200);
+            let body: Value = res.json().await?;
+            assert_eq!(body["target"], "upstream");
+        }
+    }
+
+    Ok(())
+}
+
+/// Serves HTTPS with the certificates in `tests/certs`, answering every
+/// request with the SNI name it got and whether the client sent a
+/// certificate.
+fn start_tls_upstream(port: u16, client_auth: bool) {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let certs = CertificateDer::pem_file_iter("tests/certs/server.crt")
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let key = PrivateKeyDer::from_pem_file("tests/certs/server.key").unwrap();
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .unwrap();
+    let builder = if client_auth {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter("tests/certs/ca.crt").unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let verifier =
+            rustls::server::WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .unwrap();
+        builder.with_client_cert_verifier(verifier)
+    } else {
+        builder.with_no_client_auth()
+    };
+    let config = Arc::new(builder.with_single_cert(certs, key).unwrap());
+
+    let listener = std::net::TcpListener::bind(("127.0.0.1", port)).unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let config = config.clone();
+            std::thread::spawn(move || {
+                let conn = rustls::ServerConnection::new(config).unwrap();
+                let mut tls = rustls::StreamOwned::new(conn, stream);
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0u8];
+                    if tls.read(&mut byte).unwrap_or(0) == 0 {
+                        return;
+                    }
+                    head.push(byte[0]);
+                }
+                let body = json!({
+                    "sni": tls.conn.server_name(),
+                    "client_cert": tls.conn.peer_certificates().is_some()
+                })
+                .to_string();
+                let _ = write!(
+                    tls,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                tls.conn.send_close_notify();
+                let _ = tls.flush();
+            });
+        }
+    });
+}
+
+#[tokio::test]
+async fn test_proxy_tls() -> Result<(), Box<dyn std::error::Error>> {
+    start_tls_upstream(3058, false);
+    start_tls_upstream(3059, true);
+    let _server = TestServer::start(Some(3060), Some("expectations_tls.json"));
+    wait_for_server("http://localhost:3060").await;
+
+    // Laid out like a mounted kubernetes.io/tls secret
+    let secret_dir = std::env::temp_dir().join("mimicrab-test-tls-secret");
+    std::fs::create_dir_all(&secret_dir)?;
+    for (from, to) in [
+        ("ca.crt", "ca.crt"),
+        ("client.crt", "tls.crt"),
+        ("client.key", "tls.key"),
+    ] {
+        std::fs::copy(format!("tests/certs/{}", from), secret_dir.join(to))?;
+    }
+
+    let client = reqwest::Client::new();
+    let ca = json!(["tests/certs/ca.crt"]);
+    for (path, url, tls) in [
+        ("/untrusted", "https://localhost:3058", json!(null)),
+        ("/ca", "https://localhost:3058", json!({ "ca_files": ca })),
+        (
+            "/insecure",
+            "https://localhost:3058",
+            json!({ "insecure_skip_verify": true }),
+        ),
+        (
+            "/sni",
+            "https://127.0.0.1:3058",
+            json!({ "ca_files": ca, "server_name": "upstream.test" }),
+        ),
+        (
+            "/mtls-missing",
+            "https://localhost:3059",
+            json!({ "ca_files": ca }),
+        ),
+        (
+            "/mtls",
+            "https://localhost:3059",
+            json!({
+                "ca_files": ca,
+                "cert_file": "tests/certs/client.crt",
+                "key_file": "tests/certs/client.key"
+            }),
+        ),
+        (
+            "/secret",
+            "https://localhost:3059",
+            json!({ "secret_dir": secret_dir }),
+        ),
+    ] {
+        let res = client
+            .post("http://localhost:3060/_admin/mocks")
+            .json(&json!({
+                "condition": { "path": path },
+                "response": { "proxy": { "url": url, "tls": tls } }
+            }))
+            .send()
+            .await?;
+        assert_eq!(res.status(), 201, "{}", path);
+    }
+
+    for tls in [
+        json!({ "cert_file": "tests/certs/client.crt" }),
+        json!({ "ca_files": ["tests/certs/missing.crt"] }),
+    ] {
+        let res = client
+            .post("http://localhost:3060/_admin/mocks")
+            .json(&json!({
+                "condition": { "path": "/invalid" },
+                "response": { "proxy": { "url": "https://localhost:3058", "tls": tls } }
+            }))
+            .send()
+            .await?;
+        assert_eq!(res.status(), 400);
+    }
+
+    let get = |path: &str| client.get(format!("http://localhost:3060{}", path)).send();
+
+    assert_eq!(get("/untrusted").await?.status(), 502);
+    let res = get("/ca").await?;
+    assert_eq!(res.status(), 200);
+    assert_eq!(
+        res.json::<Value>().await?,
+        json!({ "sni": "localhost", "client_cert": false })
+    );
+    assert_eq!(get("/insecure").await?.status(), 200);
+    let res = get("/sni").await?;
+    assert_eq!(res.json::<Value>().await?["sni"], "upstream.test");
+
+    assert_eq!(get("/mtls-missing").await?.status(), 502);
+    for path in ["/mtls", "/secret"] {
+        let res = get(path).await?;
+        assert_eq!(res.status(), 200, "{}", path);
+        assert_eq!(res.json::<Value>().await?["client_cert"], true);
+    }
+
+    // Changes to the secret are picked up without a restart
+    for file in ["tls.crt", "tls.key"] {
+        std::fs::remove_file(secret_dir.join(file))?;
+    }
+    assert_eq!(get("/secret").await?.status(), 502);
+    std::fs::copy("tests/certs/client.crt", secret_dir.join("tls.crt"))?;
+    std::fs::copy("tests/certs/client.key", secret_dir.join("tls.key"))?;
+    assert_eq!(get("/secret").await?.status(), 200);
+
+    std::fs::remove_dir_all(secret_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shadow_drift() -> Result<(), Box<dyn std::error::Error>> {
+    let _upstream = TestServer::start(Some(3061), Some("expectations_shadow_upstream.json"));
+    wait_for_server("http://localhost:3061").await;
+    let _server = TestServer::start(Some(3062), Some("expectations_shadow.json"));
+    wait_for_server("http://localhost:3062").await;
+
+    let client = reqwest::Client::new();
+    client
+        .post("http://localhost:3061/_admin/mocks")
+        .json(&json!({
+            "condition": { "path": "/users/*" },
+            "response": {
+                "status_code": 200,
+                "headers": { "X-Version": "2" },
+                "body": {
+                    "id": 1,
+                    "name": "Ann",
+                    "tags": ["admin"],
+                    "address": { "city": "Oslo" },
+                    "updated_at": "2024-05-01T10:00:00Z"
+                }
+            }
+        }))
+        .send()
+        .await?;
+
+    let mut ids = Vec::new();
+    for (path, response, upstream) in [
+        (
+            "/users/1",
+            json!({
+                "status_code": 201,
+                "headers": { "X-Version": "1" },
+                "body": { "id": "1", "name": "Bob", "tags": [7], "extra": true, "updated_at": 0 }
+            }),
+            "http://localhost:3061",
+        ),
+        (
+            "/users/2",
+            json!({
+                "status_code": 200,
+                "headers": { "X-Version": "2" },
+                "body": {
+                    "id": 2,
+                    "name": "Bob",
+                    "tags": [],
+                    "address": { "city": "Bergen" },
+                    "updated_at": 1714557600
+                }
+            }),
+            "http://localhost:3061",
+        ),
+        (
+            "/users/3",
+            json!({ "status_code": 200 }),
+            "http://127.0.0.1:3099",
+        ),
+    ] {
+        let mut mock = json!({ "condition": { "path": path }, "response": response });
+        mock["response"]["shadow"] = json!({
+            "upstream": { "url": upstream },
+            "headers": ["X-Version"],
+            "ignore": ["/updated_at"]
+        });
+        let res = client
+            .post("http://localhost:3062/_admin/mocks")
+            .json(&mock)
+            .send()
+            .await?;
+        assert_eq!(res.status(), 201);
+        ids.push(res.json::<Value>().await?["id"].as_u64().unwrap());
+    }
+    for ignore in ["", "updated_at"] {
+        let res = client
+            .post("http://localhost:3062/_admin/mocks")
+            .json(&json!({
+                "condition": { "path": "/invalid" },
+                "response": { "shadow": {
+                    "upstream": { "url": "http://localhost:3061" },
+                    "ignore": [ignore]
+                } }
+            }))
+            .send()
+            .await?;
+        assert_eq!(res.status(), 400, "{:?}", ignore);
+    }
+
+    // Clients get the mock, the upstream is only compared with
+    let res = client.get("http://localhost:3062/users/1").send().await?;
+    assert_eq!(res.status(), 201);
+    assert_eq!(res.json::<Value>().await?["name"], "Bob");
+    assert_eq!(
+        client
+            .get("http://localhost:3062/users/2")
+            .send()
+            .await?
+            .status(),
+        200
+    );
+    client.get("http://localhost:3062/users/3").send().await?;
+
+    // Comparisons run in the background, matches only show in the metrics
+    let expected: Vec<String> = [(ids[0], "drift"), (ids[1], "match"), (ids[2], "error")]
+        .iter()
+        .map(|(id, result)| {
+            format!(
+                r#"mimicrab_shadow_comparisons_total{{expectation="{}",result="{}"}} 1"#,
+                id, result
+            )
+        })
+        .collect();
+    for _ in 0..50 {
+        let metrics = client
+            .get("http://localhost:3062/_admin/metrics")
+            .send()
+            .await?
+            .text()
+            .await?;
+        if expected.iter().all(|line| metrics.contains(line)) {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    let metrics = client
+        .get("http://localhost:3062/_admin/metrics")
+        .send()
+        .await?
+        .text()
+        .await?;
+    for line in &expected {
+        assert!(metrics.contains(line), "{}", line);
+    }
+
+    let reports: Vec<Value> = client
+        .get("http://localhost:3062/_admin/drift")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(reports.len(), 2);
+
+    let drift = reports
+        .iter()
+        .find(|r| r["expectation_id"] == ids[0])
+        .unwrap();
+    assert_eq!(drift["path"], "/users/1");
+    assert_eq!(
+        drift["differences"],
+        json!([
+            { "kind": "status", "mock": 201, "upstream": 200 },
+            { "kind": "header", "path": "x-version", "mock": "1", "upstream": "2" },
+            { "kind": "body", "path": "/address", "mock": "missing", "upstream": "object" },
+            { "kind": "body", "path": "/extra", "mock": "boolean", "upstream": "missing" },
+            { "kind": "body", "path": "/id", "mock": "string", "upstream": "number" },
+            { "kind": "body", "path": "/tags/0", "mock": "number", "upstream": "string" }
+        ])
+    );
+    let unreachable = reports
+        .iter()
+        .find(|r| r["expectation_id"] == ids[2])
+        .unwrap();
+    assert!(
+        unreachable["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("upstream failed (refused)")
+    );
+
+    let filtered: Vec<Value> = client
+        .get(format!(
+            "http://localhost:3062/_admin/drift?expectation={}",
+            ids[2]
+        ))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(filtered.len(), 1);
+
+    let res = client
+        .delete("http://localhost:3062/_admin/drift")
+        .send()
+        .await?;
+    assert_eq!(res.status(), 204);
+    let reports: Vec<Value> = client
+        .get("http://localhost:3062/_admin/drift")
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert!(reports.is_empty());
+
+    Ok(())
+}
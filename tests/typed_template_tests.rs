@@ -10,11 +10,16 @@ struct TestServer {
 
 impl TestServer {
     fn start(port: u16, expectations_path: &str) -> Self {
+        Self::start_with_args(port, expectations_path, &[])
+    }
+
+    fn start_with_args(port: u16, expectations_path: &str, args: &[&str]) -> Self {
         let mut cmd = Command::new("target/debug/mimicrab");
         cmd.arg("--port")
             .arg(port.to_string())
             .arg("--expectations")
-            .arg(expectations_path);
+            .arg(expectations_path)
+            .args(args);
         let child = cmd
             .stdout(Stdio::inherit())
             .stderr(Stdio::inherit())
@@ -46,10 +51,9 @@ async fn wait_for_server(base_url: &str) {
             .get(format!("{}/_admin/mocks", base_url))
             .send()
             .await
+            && res.status() == 200
         {
-            if res.status() == 200 {
-                return;
-            }
+            return;
         }
         sleep(Duration::from_millis(500)).await;
     }
@@ -194,3 +198,791 @@ async fn test_typed_templates() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_templated_headers_and_status() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 3016;
+    let _server = TestServer::start(port, "expectations_templated_headers.json");
+    let base_url = format!("http://localhost:{}", port);
+    wait_for_server(&base_url).await;
+
+    let client = reqwest::Client::new();
+
+    client
+        .post(format!("{}/_admin/mocks", base_url))
+        .json(&json!({
+            "id": 1,
+            "condition": { "method": "POST", "path": "/orders/:id" },
+            "response": {
+                "status_code": "{{body.expectedStatus:int}}",
+                "headers": {
+                    "Location": "/orders/{{path[1]}}",
+                    "X-Request-Id": "{{headers.x-request-id}}"
+                },
+                "body": { "ok": true }
+            }
+        }))
+        .send()
+        .await?;
+
+    let res = client
+        .post(format!("{}/orders/77", base_url))
+        .header("X-Request-Id", "abc-123")
+        .json(&json!({ "expectedStatus": 202 }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 202);
+    assert_eq!(res.headers().get("location").unwrap(), "/orders/77");
+    assert_eq!(res.headers().get("x-request-id").unwrap(), "abc-123");
+
+    // An unusable status template falls back to the default status
+    let res = client
+        .post(format!("{}/orders/78", base_url))
+        .json(&json!({ "expectedStatus": "soon" }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_template_helpers() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 3017;
+    let _server = TestServer::start(port, "expectations_template_helpers.json");
+    let base_url = format!("http://localhost:{}", port);
+    wait_for_server(&base_url).await;
+
+    let client = reqwest::Client::new();
+
+    client
+        .post(format!("{}/_admin/mocks", base_url))
+        .json(&json!({
+            "id": 1,
+            "condition": { "method": "GET", "path": "/helpers" },
+            "response": {
+                "status_code": 200,
+                "body": {
+                    "id": "{{uuid}}",
+                    "roll": "{{randomInt 1 6}}",
+                    "token": "{{randomString 12}}",
+                    "seq": "{{counter}}",
+                    "label": "order-{{counter}}",
+                    "today": "{{now \"%Y-%m-%d\"}}",
+                    "tomorrow": "{{now \"%Y-%m-%d\" \"+1d\"}}",
+                    "epoch": "{{now \"epoch\"}}",
+                    "name": "{{faker.name}}",
+                    "email": "{{faker.email}}",
+                    "greeting": "Hello {{faker.name}}",
+                    "lorem": "{{faker.lorem 4}}"
+                }
+            }
+        }))
+        .send()
+        .await?;
+
+    let res = client.get(format!("{}/helpers", base_url)).send().await?;
+    let first: serde_json::Value = res.json().await?;
+    let res = client.get(format!("{}/helpers", base_url)).send().await?;
+    let second: serde_json::Value = res.json().await?;
+
+    assert_eq!(first["id"].as_str().unwrap().len(), 36);
+    assert_ne!(first["id"], second["id"]);
+    let roll = first["roll"].as_i64().unwrap();
+    assert!((1..=6).contains(&roll));
+    assert_eq!(first["token"].as_str().unwrap().len(), 12);
+    assert_eq!(first["seq"], 1);
+    assert_eq!(second["seq"], 2);
+    assert_eq!(second["label"], "order-2");
+    assert_eq!(
+        first["today"],
+        chrono::Utc::now().format("%Y-%m-%d").to_string()
+    );
+    assert_ne!(first["today"], first["tomorrow"]);
+    assert!(first["epoch"].is_number());
+    assert!(!first["name"].as_str().unwrap().is_empty());
+    assert!(first["email"].as_str().unwrap().contains('@'));
+    assert!(first["greeting"].as_str().unwrap().starts_with("Hello "));
+    assert_eq!(first["lorem"].as_str().unwrap().split(' ').count(), 4);
+
+    // Bad formats and offsets are rejected up front...
+    for now in [r#"{{now "%Q"}}"#, r#"{{now "iso" "+999999999999999w"}}"#] {
+        let res = client
+            .post(format!("{}/_admin/mocks", base_url))
+            .json(&json!({
+                "condition": { "path": "/bad-now" },
+                "response": { "status_code": 200, "body": { "at": now } }
+            }))
+            .send()
+            .await?;
+        assert_eq!(res.status(), 400, "{}", now);
+    }
+
+    // ...and render as nothing when they come from the request
+    client
+        .post(format!("{}/_admin/mocks", base_url))
+        .json(&json!({
+            "id": 2,
+            "condition": { "method": "POST", "path": "/dynamic-now" },
+            "response": {
+                "status_code": 200,
+                "body_type": "template",
+                "body": "[{{now body.fmt body.offset}}]"
+            }
+        }))
+        .send()
+        .await?;
+    for request in [
+        json!({ "fmt": "%Q" }),
+        json!({ "fmt": "iso", "offset": "+999999999999999w" }),
+    ] {
+        let res = client
+            .post(format!("{}/dynamic-now", base_url))
+            .json(&request)
+            .send()
+            .await?;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.text().await?, "[]");
+    }
+    let res = client.get(format!("{}/helpers", base_url)).send().await?;
+    let third: serde_json::Value = res.json().await?;
+    assert_eq!(third["id"].as_str().unwrap().len(), 36);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_request_context_templates() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 3018;
+    let _server = TestServer::start(port, "expectations_request_context.json");
+    let base_url = format!("http://localhost:{}", port);
+    wait_for_server(&base_url).await;
+
+    let client = reqwest::Client::new();
+    let admin_url = format!("{}/_admin/mocks", base_url);
+
+    client
+        .post(&admin_url)
+        .json(&json!({
+            "id": 1,
+            "condition": { "path": "/context" },
+            "response": {
+                "status_code": 200,
+                "body": {
+                    "auth": "{{headers.authorization}}",
+                    "page": "{{query.page:int}}",
+                    "sort": "{{query.sort}}",
+                    "method": "{{method}}",
+                    "path": "{{path}}",
+                    "session": "{{cookies.session}}",
+                    "raw": "{{rawBody}}",
+                    "summary": "{{method}} {{path}}?page={{query.page}}"
+                }
+            }
+        }))
+        .send()
+        .await?;
+
+    client
+        .post(&admin_url)
+        .json(&json!({
+            "id": 2,
+            "condition": { "path": "/context-lua" },
+            "response": {
+                "script": "return { status = 200, body = { page = request.query.page, session = request.cookies.session, raw = request.rawBody } }"
+            }
+        }))
+        .send()
+        .await?;
+
+    let res = client
+        .put(format!("{}/context?page=3&sort=desc", base_url))
+        .header("Authorization", "Bearer xyz")
+        .header("Cookie", "theme=dark; session=s-42")
+        .body("plain text")
+        .send()
+        .await?;
+    let body: serde_json::Value = res.json().await?;
+    assert_eq!(body["auth"], "Bearer xyz");
+    assert_eq!(body["page"], 3);
+    assert_eq!(body["sort"], "desc");
+    assert_eq!(body["method"], "PUT");
+    assert_eq!(body["path"], "/context");
+    assert_eq!(body["session"], "s-42");
+    assert_eq!(body["raw"], "plain text");
+    assert_eq!(body["summary"], "PUT /context?page=3");
+
+    // Lua scripts see the same request context
+    let res = client
+        .post(format!("{}/context-lua?page=7", base_url))
+        .header("Cookie", "session=s-43")
+        .body("raw payload")
+        .send()
+        .await?;
+    let body: serde_json::Value = res.json().await?;
+    assert_eq!(body["page"], "7");
+    assert_eq!(body["session"], "s-43");
+    assert_eq!(body["raw"], "raw payload");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_block_templates() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 3019;
+    let _server = TestServer::start(port, "expectations_block_templates.json");
+    let base_url = format!("http://localhost:{}", port);
+    wait_for_server(&base_url).await;
+
+    let client = reqwest::Client::new();
+
+    let template = r#"{
+  "customer": "{{default body.name "anon"}}",
+  "tier": "{{#if body.premium}}gold{{else}}standard{{/if}}",
+  "skus": [{{#each body.items}}"{{this.sku}}"{{#unless @last}}, {{/unless}}{{/each}}],
+  "missing": "{{body.nothing}}",
+  "page": {{default query.page 1}}
+}"#;
+
+    client
+        .post(format!("{}/_admin/mocks", base_url))
+        .json(&json!({
+            "id": 1,
+            "condition": { "method": "POST", "path": "/checkout" },
+            "response": {
+                "status_code": 200,
+                "body_type": "template",
+                "body": template
+            }
+        }))
+        .send()
+        .await?;
+
+    let res = client
+        .post(format!("{}/checkout?page=2", base_url))
+        .json(&json!({
+            "premium": true,
+            "items": [{ "sku": "A-1" }, { "sku": "B-2" }]
+        }))
+        .send()
+        .await?;
+    assert_eq!(
+        res.headers().get("content-type").unwrap(),
+        "application/json"
+    );
+    let body: serde_json::Value = res.json().await?;
+    assert_eq!(body["customer"], "anon");
+    assert_eq!(body["tier"], "gold");
+    assert_eq!(body["skus"], json!(["A-1", "B-2"]));
+    assert_eq!(body["missing"], "");
+    assert_eq!(body["page"], 2);
+
+    let res = client
+        .post(format!("{}/checkout", base_url))
+        .json(&json!({ "name": "Ada", "items": [] }))
+        .send()
+        .await?;
+    let body: serde_json::Value = res.json().await?;
+    assert_eq!(body["customer"], "Ada");
+    assert_eq!(body["tier"], "standard");
+    assert_eq!(body["skus"], json!([]));
+    assert_eq!(body["page"], 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_template_filters() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 3027;
+    let _server = TestServer::start(port, "expectations_template_filters.json");
+    let base_url = format!("http://localhost:{}", port);
+    wait_for_server(&base_url).await;
+
+    let client = reqwest::Client::new();
+    let admin_url = format!("{}/_admin/mocks", base_url);
+
+    client
+        .post(&admin_url)
+        .json(&json!({
+            "id": 1,
+            "condition": { "method": "POST", "path": "/filters" },
+            "response": {
+                "status_code": 200,
+                "body": {
+                    "name": "{{body.name:lower:trim}}",
+                    "shout": "{{body.name:trim:upper}}",
+                    "encoded": "{{body.word:base64}}",
+                    "decoded": "{{body.secret:unbase64}}",
+                    "query": "q={{body.search:urlencode}}",
+                    "tags_json": "{{body.tags:json}}",
+                    "tag_count": "{{body.tags:length}}",
+                    "hash": "{{body.word:sha256}}",
+                    "day": "{{body.created:date:%d/%m/%Y}}",
+                    "time": "{{body.created:date:\"%H:%M\"}}",
+                    "next_page": "{{body.page:add:1}}",
+                    "total": "{{body.price:mul:3}}",
+                    "greeting": "Hi {{body.name:trim:upper}}!"
+                }
+            }
+        }))
+        .send()
+        .await?;
+
+    let res = client
+        .post(format!("{}/filters", base_url))
+        .json(&json!({
+            "name": "  Ada Lovelace ",
+            "word": "hello",
+            "secret": "c2VjcmV0",
+            "search": "a b&c",
+            "tags": ["x", "y"],
+            "created": "2024-03-05T14:30:00Z",
+            "page": 4,
+            "price": 2.5
+        }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 200);
+    let body: serde_json::Value = res.json().await?;
+    assert_eq!(body["name"], "ada lovelace");
+    assert_eq!(body["shout"], "ADA LOVELACE");
+    assert_eq!(body["encoded"], "aGVsbG8=");
+    assert_eq!(body["decoded"], "secret");
+    assert_eq!(body["query"], "q=a%20b%26c");
+    assert_eq!(body["tags_json"], "[\"x\",\"y\"]");
+    assert_eq!(body["tag_count"], 2);
+    assert_eq!(
+        body["hash"],
+        "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+    );
+    assert_eq!(body["day"], "05/03/2024");
+    assert_eq!(body["time"], "14:30");
+    assert_eq!(body["next_page"], 5);
+    assert_eq!(body["total"], 7.5);
+    assert_eq!(body["greeting"], "Hi ADA LOVELACE!");
+
+    // Unknown filters are rejected when the mock is created
+    let res = client
+        .post(&admin_url)
+        .json(&json!({
+            "id": 2,
+            "condition": { "method": "POST", "path": "/bad-filter" },
+            "response": { "status_code": 200, "body": { "x": "{{body.name:shuffle}}" } }
+        }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 400);
+    assert!(res.text().await?.contains("unknown filter 'shuffle'"));
+
+    // Filters that cannot be applied are reported at request time
+    client
+        .post(&admin_url)
+        .json(&json!({
+            "id": 3,
+            "condition": { "method": "POST", "path": "/bad-operand" },
+            "response": { "status_code": 200, "body": { "x": "{{body.name:add:1}}" } }
+        }))
+        .send()
+        .await?;
+    let res = client
+        .post(format!("{}/bad-operand", base_url))
+        .json(&json!({ "name": "Ada" }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 500);
+    assert!(res.text().await?.contains("filter 'add' cannot use"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_template_validation() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 3028;
+    // Files are not validated, so a bad header name can get in this way
+    let path = "expectations_template_validation.json";
+    let from_file = json!([{
+        "id": 20,
+        "condition": { "path": "/bad-header" },
+        "response": { "headers": { "Bad Header": "x", "X-Ok": "1" }, "body": "ok" }
+    }]);
+    std::fs::write(path, from_file.to_string())?;
+    let _server = TestServer::start(port, path);
+    let base_url = format!("http://localhost:{}", port);
+    wait_for_server(&base_url).await;
+
+    let client = reqwest::Client::new();
+    let admin_url = format!("{}/_admin/mocks", base_url);
+
+    // Invalid header names are skipped when serving and refused when added
+    let res = client
+        .get(format!("{}/bad-header", base_url))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["x-ok"], "1");
+    let res = client
+        .post(&admin_url)
+        .json(&json!({
+            "condition": { "path": "/bad-header" },
+            "response": { "headers": { "Bad Header": "x" }, "body": "ok" }
+        }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 400);
+    let error: serde_json::Value = res.json().await?;
+    assert!(
+        error["error"]
+            .as_str()
+            .unwrap()
+            .contains("invalid header name 'Bad Header'")
+    );
+
+    // Braces that are not markers may be meant literally...
+    let res = client
+        .post(&admin_url)
+        .json(&json!({
+            "condition": { "path": "/literal" },
+            "response": { "body": { "greeting": "Hello {{ name }}" } }
+        }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 201);
+    let res = client.get(format!("{}/literal", base_url)).send().await?;
+    let body: serde_json::Value = res.json().await?;
+    assert_eq!(body["greeting"], "Hello {{ name }}");
+
+    // ...unless the mock is strict, where they are typos
+    let res = client
+        .post(&admin_url)
+        .json(&json!({
+            "condition": { "path": "/typo" },
+            "response": { "strict_templates": true, "body": { "name": "{{bodyy.name}}" } }
+        }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 400);
+    let error: serde_json::Value = res.json().await?;
+    assert!(
+        error["error"]
+            .as_str()
+            .unwrap()
+            .contains("unknown template reference {{bodyy.name}}")
+    );
+
+    // Broken header and jitter templates are caught as well
+    let res = client
+        .post(&admin_url)
+        .json(&json!({
+            "condition": { "path": "/header" },
+            "response": {
+                "headers": { "X-Id": "{{headers.x-id:nope}}" },
+                "body": "ok"
+            }
+        }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 400);
+
+    let res = client
+        .post(&admin_url)
+        .json(&json!({
+            "condition": { "path": "/jitter" },
+            "response": {
+                "body": "ok",
+                "jitter": { "probability": 0.5, "body": "{{path:nope}}" }
+            }
+        }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 400);
+
+    // Date filters need a valid format
+    let res = client
+        .post(&admin_url)
+        .json(&json!({
+            "condition": { "path": "/date" },
+            "response": { "body": { "day": "{{body.ts:date:\"%Q\"}}" } }
+        }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 400);
+    let error: serde_json::Value = res.json().await?;
+    assert!(
+        error["error"]
+            .as_str()
+            .unwrap()
+            .contains("invalid date format '%Q'")
+    );
+
+    // Block templates must compile
+    let res = client
+        .post(&admin_url)
+        .json(&json!({
+            "id": 10,
+            "condition": { "path": "/block" },
+            "response": { "body_type": "template", "body": "{{#if body.x}}yes{{/if}}" }
+        }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 201);
+
+    let res = client
+        .put(format!("{}/10", admin_url))
+        .json(&json!({
+            "id": 10,
+            "condition": { "path": "/block" },
+            "response": { "body_type": "template", "body": "{{#if body.x}}yes" }
+        }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 400);
+
+    let mocks: Vec<serde_json::Value> = client.get(&admin_url).send().await?.json().await?;
+    assert_eq!(mocks.len(), 3);
+    assert_eq!(mocks[2]["response"]["body"], "{{#if body.x}}yes{{/if}}");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_strict_templates() -> Result<(), Box<dyn std::error::Error>> {
+    let lenient_port = 3029;
+    let strict_port = 3030;
+    let _lenient = TestServer::start(lenient_port, "expectations_strict_lenient.json");
+    let _strict = TestServer::start_with_args(
+        strict_port,
+        "expectations_strict_global.json",
+        &["--strict-templates"],
+    );
+    let client = reqwest::Client::new();
+
+    let mocks = [
+        json!({
+            "id": 1,
+            "condition": { "path": "/default" },
+            "response": { "body": { "name": "{{body.missing}}", "page": "page {{query.page}}" } }
+        }),
+        json!({
+            "id": 2,
+            "condition": { "path": "/strict" },
+            "response": {
+                "strict_templates": true,
+                "body": { "name": "{{body.missing}}" }
+            }
+        }),
+        json!({
+            "id": 3,
+            "condition": { "path": "/lenient" },
+            "response": {
+                "strict_templates": false,
+                "body": { "name": "{{body.missing}}" }
+            }
+        }),
+        json!({
+            "id": 4,
+            "condition": { "path": "/block" },
+            "response": {
+                "body_type": "template",
+                "body": "{{default body.name \"anon\"}} {{body.missing}}"
+            }
+        }),
+        json!({
+            "id": 5,
+            "condition": { "path": "/block-default" },
+            "response": {
+                "body_type": "template",
+                "body": "{{default body.name \"anon\"}}"
+            }
+        }),
+    ];
+
+    for port in [lenient_port, strict_port] {
+        let base_url = format!("http://localhost:{}", port);
+        wait_for_server(&base_url).await;
+        for mock in &mocks {
+            let res = client
+                .post(format!("{}/_admin/mocks", base_url))
+                .json(mock)
+                .send()
+                .await?;
+            assert_eq!(res.status(), 201);
+        }
+    }
+
+    // Without strict mode, missing values become null
+    let lenient = format!("http://localhost:{}", lenient_port);
+    let body: serde_json::Value = client
+        .get(format!("{}/default", lenient))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(body["name"], serde_json::Value::Null);
+    assert_eq!(body["page"], "page null");
+
+    let res = client.get(format!("{}/strict", lenient)).send().await?;
+    assert_eq!(res.status(), 500);
+    assert!(
+        res.text()
+            .await?
+            .contains("unresolved reference {{body.missing}}")
+    );
+
+    let res = client.get(format!("{}/block", lenient)).send().await?;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.text().await?, "anon ");
+
+    // With --strict-templates, every mock is strict unless it opts out
+    let strict = format!("http://localhost:{}", strict_port);
+    let res = client.get(format!("{}/default", strict)).send().await?;
+    assert_eq!(res.status(), 500);
+    assert!(
+        res.text()
+            .await?
+            .contains("unresolved reference {{body.missing}}")
+    );
+
+    let body: serde_json::Value = client
+        .get(format!("{}/lenient", strict))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(body["name"], serde_json::Value::Null);
+
+    let res = client.get(format!("{}/block", strict)).send().await?;
+    assert_eq!(res.status(), 500);
+
+    // Helpers that handle missing values still work in strict mode
+    let res = client
+        .get(format!("{}/block-default", strict))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.text().await?, "anon");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_json_path_templates() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 3031;
+    let _server = TestServer::start(port, "expectations_json_path.json");
+    let base_url = format!("http://localhost:{}", port);
+    wait_for_server(&base_url).await;
+
+    let client = reqwest::Client::new();
+    let admin_url = format!("{}/_admin/mocks", base_url);
+
+    let res = client
+        .post(&admin_url)
+        .json(&json!({
+            "id": 1,
+            "condition": { "method": "POST", "path": "/order" },
+            "response": {
+                "status_code": 200,
+                "body": {
+                    "price": "{{$.items[?(@.sku=='A-1')].price}}",
+                    "cheap": "{{$.items[?(@.price < 10)].sku}}",
+                    "last": "{{$.items[-1].sku}}",
+                    "first_two": "{{$.items[0:2].sku}}",
+                    "content_type": "{{body['content-type']}}",
+                    "spaced": "{{body['delivery address'].city:upper}}",
+                    "unicode": "{{body.größe}}",
+                    "all_skus": "{{$..sku:length}}",
+                    "summary": "{{body['content-type']}} for {{$.items[-1].sku}}",
+                    "listed": "skus {{$.items[0:2].sku}}",
+                    "none": "{{$.items[?(@.sku=='Z-9')].price}}"
+                }
+            }
+        }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 201);
+
+    let res = client
+        .post(format!("{}/order", base_url))
+        .json(&json!({
+            "content-type": "order",
+            "delivery address": { "city": "Lyon" },
+            "größe": "XL",
+            "items": [
+                { "sku": "A-1", "price": 9.5 },
+                { "sku": "B-2", "price": 20 },
+                { "sku": "C-3", "price": 5 }
+            ]
+        }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 200);
+    let body: serde_json::Value = res.json().await?;
+    assert_eq!(body["price"], 9.5);
+    assert_eq!(body["cheap"], json!(["A-1", "C-3"]));
+    assert_eq!(body["last"], "C-3");
+    assert_eq!(body["first_two"], json!(["A-1", "B-2"]));
+    assert_eq!(body["content_type"], "order");
+    assert_eq!(body["spaced"], "LYON");
+    assert_eq!(body["unicode"], "XL");
+    assert_eq!(body["all_skus"], 3);
+    assert_eq!(body["summary"], "order for C-3");
+    assert_eq!(body["listed"], r#"skus ["A-1","B-2"]"#);
+    assert_eq!(body["none"], serde_json::Value::Null);
+
+    // Malformed expressions are rejected up front
+    let res = client
+        .post(&admin_url)
+        .json(&json!({
+            "condition": { "path": "/bad-path" },
+            "response": { "body": { "x": "{{$.items[?(@.sku==)]}}" } }
+        }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), 400);
+    assert!(res.text().await?.contains("invalid JSONPath"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_request_data_is_not_expanded() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 3065;
+    let _server = TestServer::start(port, "expectations_no_expansion.json");
+    let base_url = format!("http://localhost:{}", port);
+    wait_for_server(&base_url).await;
+
+    let client = reqwest::Client::new();
+    for (id, strict) in [(1, false), (2, true)] {
+        let res = client
+            .post(format!("{}/_admin/mocks", base_url))
+            .json(&json!({
+                "id": id,
+                "condition": { "method": "POST", "path": format!("/echo/{}", id) },
+                "response": {
+                    "status_code": 200,
+                    "strict_templates": strict,
+                    "body": { "greeting": "Hello {{body.name}}!" }
+                }
+            }))
+            .send()
+            .await?;
+        assert_eq!(res.status(), 201);
+    }
+
+    // Markers in request data are echoed as they are, strict or not, so
+    // echoing a field cannot leak the caller's headers or cookies
+    let name = "{{uuid}} {{body.missing}} {{$.name}} {{headers.authorization}} {{cookies.session}} {{rawBody}}";
+    for id in [1, 2] {
+        let res = client
+            .post(format!("{}/echo/{}", base_url, id))
+            .header("Authorization", "Bearer secret")
+            .header("Cookie", "session=s3cr3t")
+            .json(&json!({ "name": name }))
+            .send()
+            .await?;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = res.json().await?;
+        assert_eq!(body["greeting"], format!("Hello {}!", name));
+    }
+
+    Ok(())
+}
@@ -284,8 +284,8 @@ function addLogEntry(log) {
     entry.className = 'log-entry';
 
     const time = new Date(log.timestamp).toLocaleTimeString();
-    const statusClass = log.matched ? 'log-matched' : 'log-missed';
-    const statusText = log.matched ? 'MATCH' : 'MISS';
+    const statusClass = log.matched ? 'log-matched' : (log.passthrough ? 'log-passthrough' : 'log-missed');
+    const statusText = log.matched ? 'MATCH' : (log.passthrough ? 'PASSTHROUGH' : 'MISS');
 
     entry.innerHTML = `
         <span class="log-time">[${time}]</span>
@@ -293,6 +293,7 @@ function addLogEntry(log) {
         <span class="log-path">${log.path}</span>
         <span class="${statusClass}">${statusText}</span>
         ${log.expectation_id ? `<span class="log-time">(ID: ${log.expectation_id})</span>` : ''}
+        ${log.error ? `<span class="log-missed">${log.error}</span>` : ''}
     `;
 
     logsList.prepend(entry);
@@ -304,7 +305,22 @@ function addLogEntry(log) {
 }
 
 // Modal Handlers
+
+// Mock the form was opened with; saving merges the form into it so that
+// settings the form has no inputs for are kept
+let editingMock = null;
+
+// Disables an input whose value the form cannot show, such as a templated
+// status or a latency distribution, so that it is saved unchanged
+function lockInput(input, locked) {
+    input.disabled = locked;
+    input.title = locked ? 'Set through the admin API, kept as is' : '';
+}
+
 function openModal(mock = null, isClone = false) {
+    editingMock = mock;
+    [jitterToggle, ...['mock-status', 'mock-latency', 'mock-jitter-status', 'mock-jitter-latency'].map(id => document.getElementById(id))]
+        .forEach(input => lockInput(input, false));
     const isEdit = !!mock && !isClone;
     if (isClone) {
         document.getElementById('modal-title').textContent = 'Clone Mock';
@@ -322,18 +338,30 @@ function openModal(mock = null, isClone = false) {
         document.getElementById('mock-method').value = mock.condition.method;
         document.getElementById('mock-path').value = mock.condition.path;
         document.getElementById('mock-req-body').value = mock.condition.body ? JSON.stringify(mock.condition.body, null, 2) : '';
-        document.getElementById('mock-status').value = mock.response.status_code || 200;
-        document.getElementById('mock-latency').value = mock.response.latency || 0;
+        const status = mock.response.status_code;
+        lockInput(document.getElementById('mock-status'), typeof status === 'string');
+        document.getElementById('mock-status').value = typeof status === 'number' ? status : 200;
+        const latency = mock.response.latency;
+        lockInput(document.getElementById('mock-latency'), typeof latency === 'object' && latency !== null);
+        document.getElementById('mock-latency').value = typeof latency === 'number' ? latency : 0;
         document.getElementById('mock-body-type').value = mock.response.body_type || 'json';
 
-        // Jitter
-        if (mock.response.jitter) {
+        // Jitter, weighted outcomes are kept as they are
+        if (Array.isArray(mock.response.jitter)) {
+            lockInput(jitterToggle, true);
+            jitterToggle.checked = true;
+            jitterSettings.classList.add('disabled');
+        } else if (mock.response.jitter) {
             jitterToggle.checked = true;
             jitterSettings.classList.remove('disabled');
             document.getElementById('mock-jitter-prob').value = (mock.response.jitter.probability * 100).toFixed(0);
-            document.getElementById('mock-jitter-status').value = mock.response.jitter.status_code || 500;
+            const jitterStatus = mock.response.jitter.status_code;
+            lockInput(document.getElementById('mock-jitter-status'), typeof jitterStatus === 'string');
+            document.getElementById('mock-jitter-status').value = typeof jitterStatus === 'number' ? jitterStatus : 500;
             document.getElementById('mock-jitter-body-type').value = mock.response.jitter.body_type || 'json';
-            document.getElementById('mock-jitter-latency').value = mock.response.jitter.latency || 0;
+            const jitterLatency = mock.response.jitter.latency;
+            lockInput(document.getElementById('mock-jitter-latency'), typeof jitterLatency === 'object' && jitterLatency !== null);
+            document.getElementById('mock-jitter-latency').value = typeof jitterLatency === 'number' ? jitterLatency : 0;
 
             const jitterResBody = mock.response.jitter.body;
             if (jitterResBody !== undefined && jitterResBody !== null) {
@@ -475,8 +503,6 @@ function setupEventListeners() {
     jitterToggle.onchange = (e) => {
         if (e.target.checked) {
             jitterSettings.classList.remove('disabled');
-            proxyToggle.checked = false;
-            proxySettings.classList.add('disabled');
             luaToggle.checked = false;
             luaSettings.classList.add('disabled');
         } else {
@@ -487,8 +513,6 @@ function setupEventListeners() {
     proxyToggle.onchange = (e) => {
         if (e.target.checked) {
             proxySettings.classList.remove('disabled');
-            jitterToggle.checked = false;
-            jitterSettings.classList.add('disabled');
             luaToggle.checked = false;
             luaSettings.classList.add('disabled');
         } else {
@@ -629,7 +653,8 @@ function setupEventListeners() {
             if (key) requestHeaders[key] = value;
         });
 
-        const jitterEnabled = jitterToggle.checked;
+        const original = editingMock || { condition: {}, response: {} };
+        const jitterEnabled = jitterToggle.checked && !jitterToggle.disabled;
         let jitterConfig = undefined;
         if (jitterEnabled) {
             const jitterBodyStr = document.getElementById('mock-jitter-body').value;
@@ -658,11 +683,16 @@ function setupEventListeners() {
             });
 
             jitterConfig = {
+                ...original.response.jitter,
                 probability: parseFloat(document.getElementById('mock-jitter-prob').value) / 100,
-                status_code: parseInt(document.getElementById('mock-jitter-status').value),
+                status_code: document.getElementById('mock-jitter-status').disabled
+                    ? original.response.jitter.status_code
+                    : parseInt(document.getElementById('mock-jitter-status').value),
                 body: jitterBody,
                 body_type: jitterBodyType,
-                latency: jitterLatencyVal ? parseInt(jitterLatencyVal) : undefined,
+                latency: document.getElementById('mock-jitter-latency').disabled
+                    ? original.response.jitter.latency
+                    : jitterLatencyVal ? parseInt(jitterLatencyVal) : undefined,
                 headers: Object.keys(jitterResponseHeaders).length > 0 ? jitterResponseHeaders : undefined
             };
         }
@@ -678,6 +708,7 @@ function setupEventListeners() {
             });
 
             proxyConfig = {
+                ...original.response.proxy,
                 url: document.getElementById('mock-proxy-url').value.trim(),
                 headers: Object.keys(proxyHeaders).length > 0 ? proxyHeaders : undefined
             };
@@ -686,15 +717,21 @@ function setupEventListeners() {
         const mock = {
             id: idVal ? parseInt(idVal) : Math.floor(Math.random() * 1000000),
             condition: {
+                ...original.condition,
                 method: document.getElementById('mock-method').value,
                 path: document.getElementById('mock-path').value,
                 body: requestBody,
                 headers: Object.keys(requestHeaders).length > 0 ? requestHeaders : undefined
             },
             response: {
-                status_code: parseInt(document.getElementById('mock-status').value),
-                latency: latencyVal ? parseInt(latencyVal) : undefined,
-                jitter: jitterConfig,
+                ...original.response,
+                status_code: document.getElementById('mock-status').disabled
+                    ? original.response.status_code
+                    : parseInt(document.getElementById('mock-status').value),
+                latency: document.getElementById('mock-latency').disabled
+                    ? original.response.latency
+                    : latencyVal ? parseInt(latencyVal) : undefined,
+                jitter: jitterToggle.disabled ? original.response.jitter : jitterConfig,
                 proxy: proxyConfig,
                 headers: Object.keys(responseHeaders).length > 0 ? responseHeaders : undefined,
                 body: responseBody,